@@ -0,0 +1,95 @@
+#!/usr/bin/env python3
+"""Measure the peak memory used to patch a large ICE file.
+
+Packs a fixture ICE of two groups of large, incompressible entries with the
+`pack` command of the first build given, and a patch replacing every other
+entry of each group and adding one more. The patch is then applied to a fresh
+copy of the fixture by each build in turn, and the peak resident set size of
+each is reported.
+
+To compare with an older build, check it out into a worktree and build it:
+
+    git worktree add /tmp/old <commit>
+    cargo build --release --manifest-path /tmp/old/Cargo.toml
+    scripts/peak_memory.py target/release/pso2-modpatcher /tmp/old/target/release/pso2-modpatcher
+
+Linux only, as ru_maxrss is read as KiB.
+"""
+
+import argparse
+import os
+import shutil
+import subprocess
+import sys
+import tempfile
+
+ICE_NAME = "0123456789abcdef0123456789abcdef"
+
+
+def write_entries(group_dir, names, size):
+    os.makedirs(group_dir, exist_ok=True)
+    for name in names:
+        with open(os.path.join(group_dir, name), "wb") as f:
+            f.write(os.urandom(size))
+
+
+def make_fixture(work, build, entries, entry_size, encrypt):
+    names = ["e{:04}.bin".format(i) for i in range(entries)]
+    src = os.path.join(work, "src")
+    for group in ("1", "2"):
+        write_entries(os.path.join(src, group), names, entry_size)
+    ice = os.path.join(work, "fixture.ice")
+    pack = [build, "pack", src, ice] + (["--encrypt"] if encrypt else [])
+    subprocess.run(pack, check=True)
+    shutil.rmtree(src)
+
+    patch_ice = os.path.join(work, "patch", "win32", ICE_NAME + "_ice")
+    for group in ("1", "2"):
+        write_entries(os.path.join(patch_ice, group), names[::2] + ["added.bin"], entry_size)
+    return ice, os.path.join(work, "patch")
+
+
+def patch_command(build, patch, datadir):
+    # patching moved behind an apply command; older builds take the paths alone
+    usage = subprocess.run([build, "--help"], check=True, capture_output=True, text=True).stdout
+    apply = ["apply"] if "\n    apply " in usage else []
+    return [build, "--no-backup"] + apply + [patch, datadir]
+
+
+def peak_rss_mib(build, ice, patch, work):
+    datadir = os.path.join(work, "data")
+    shutil.rmtree(datadir, ignore_errors=True)
+    os.makedirs(os.path.join(datadir, "win32"))
+    shutil.copy(ice, os.path.join(datadir, "win32", ICE_NAME))
+
+    process = subprocess.Popen(patch_command(build, patch, datadir))
+    _, status, usage = os.wait4(process.pid, 0)
+    process.returncode = os.waitstatus_to_exitcode(status)
+    if process.returncode != 0:
+        sys.exit("{} failed with exit code {}".format(build, process.returncode))
+    return usage.ru_maxrss / 1024
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("builds", nargs="+", help="pso2-modpatcher binaries to measure")
+    parser.add_argument("--entries", type=int, default=80, help="entries in each group (default 80)")
+    parser.add_argument("--entry-mib", type=int, default=2, help="size of each entry in MiB (default 2)")
+    parser.add_argument("--encrypt", action="store_true", help="encrypt the fixture")
+    args = parser.parse_args()
+
+    entry_size = args.entry_mib * 1024 * 1024
+    with tempfile.TemporaryDirectory(prefix="pso2-modpatcher-memory-") as work:
+        ice, patch = make_fixture(work, args.builds[0], args.entries, entry_size, args.encrypt)
+        print("Fixture: {:.0f} MiB, 2 groups of {} x {} MiB entries{}".format(
+            os.path.getsize(ice) / (1024 * 1024),
+            args.entries,
+            args.entry_mib,
+            ", encrypted" if args.encrypt else "",
+        ))
+        for build in args.builds:
+            print("{}: peak RSS {:.0f} MiB".format(build, peak_rss_mib(build, ice, patch, work)))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,403 @@
+//! Backups of original ICE files, kept in a content-addressed store.
+//!
+//! Originals are stored once per distinct content, as blobs named by their
+//! SHA-256 hash under `blobs/`. The index records, for every generation, which
+//! blob held each data directory path. A generation is started by every run
+//! that backs anything up and is named for the time the run started, so the
+//! originals from before each game update stay available until pruned.
+//!
+//! Originals are moved into the store, or copied and verified when it is on
+//! another filesystem. They may instead be reflinked or hard linked, which
+//! leaves the original in place until the patched file replaces it.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::HashWriter;
+use crate::state::{self, InstallState};
+
+/// File name of the index within a backup directory.
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// How to link backups to their originals instead of moving them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupLink {
+    /// Share the original's data copy-on-write, where the filesystem allows.
+    Reflink,
+    /// Share the original's file. Only safe if nothing modifies ICE files in
+    /// place, as that would modify the backup too.
+    Hard,
+}
+
+impl FromStr for BackupLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BackupLink, String> {
+        match s {
+            "reflink" => Ok(BackupLink::Reflink),
+            "hard" => Ok(BackupLink::Hard),
+            _ => Err(format!("unknown backup link type {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BackupIndex {
+    /// Hashes of the originals backed up in each generation, keyed by their
+    /// `/`-separated path relative to the data directory.
+    generations: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+pub struct BackupStore {
+    root: PathBuf,
+    index: BackupIndex,
+    generation: String,
+    link: Option<BackupLink>,
+    /// Set once linking has failed, as it will fail for every other file in
+    /// the same place too.
+    link_failed: Cell<bool>,
+}
+
+impl BackupStore {
+    /// Open the backup directory `root`, creating it if needed, and choose the
+    /// name of the generation this run backs up into.
+    pub fn new(root: &Path, link: Option<BackupLink>) -> anyhow::Result<BackupStore> {
+        if root.exists() && !root.is_dir() {
+            bail!("Backup path {} is not a directory", root.to_string_lossy());
+        }
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Failed to make backup directory {}", root.to_string_lossy()))?;
+        // recorded backup paths are absolute, so that they can be matched to
+        // blobs however the directory is named on later runs
+        let root = std::fs::canonicalize(root)
+            .with_context(|| format!("Failed to resolve backup directory {}", root.to_string_lossy()))?;
+
+        let index_path = root.join(INDEX_FILE_NAME);
+        let index: BackupIndex = if index_path.exists() {
+            let contents = std::fs::read(&index_path)
+                .with_context(|| format!("Failed to read backup index {}", index_path.to_string_lossy()))?;
+            serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse backup index {}", index_path.to_string_lossy()))?
+        } else {
+            BackupIndex::default()
+        };
+
+        let base = generation_name(state::now());
+        let mut generation = base.clone();
+        let mut n = 1;
+        while index.generations.contains_key(&generation) {
+            // padded so that `-010` still sorts after `-002`
+            n += 1;
+            generation = format!("{}-{:03}", base, n);
+        }
+        Ok(BackupStore {
+            root,
+            index,
+            generation,
+            link,
+            link_failed: Cell::new(false),
+        })
+    }
+
+    /// Open the backup directory `root` to read originals from, or `None` if
+    /// it isn't one.
+    pub fn open_existing(root: &Path) -> anyhow::Result<Option<BackupStore>> {
+        if !root.join(INDEX_FILE_NAME).is_file() {
+            return Ok(None);
+        }
+        BackupStore::new(root, None).map(Some)
+    }
+
+    /// Whether the index has a generation named `generation`.
+    pub fn has_generation(&self, generation: &str) -> bool {
+        self.index.generations.contains_key(generation)
+    }
+
+    /// Names of the generations in the index, oldest first.
+    pub fn generations(&self) -> impl Iterator<Item = &str> {
+        self.index.generations.keys().map(|g| g.as_str())
+    }
+
+    /// The blob holding the original backed up for `key` in `generation`, or
+    /// in the newest generation that backed it up, if it is still stored.
+    pub fn original(&self, key: &str, generation: Option<&str>) -> Option<PathBuf> {
+        let hash = match generation {
+            Some(generation) => self.index.generations.get(generation)?.get(key)?,
+            None => self.index.generations.values().rev().find_map(|g| g.get(key))?,
+        };
+        Some(self.blob_path(hash)).filter(|b| b.is_file())
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let contents = serde_json::to_vec_pretty(&self.index)
+            .with_context(|| "Failed to serialize backup index")?;
+        state::write_atomic(&self.root.join(INDEX_FILE_NAME), &contents)
+            .with_context(|| "Failed to write backup index")
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.root.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    /// Back up `file`, whose path relative to the data directory is `key` and
+    /// whose contents hash to `hash`, into this run's generation. Returns the
+    /// path of the blob holding it. Unless the original was already stored or
+    /// the backup is linked, `file` is moved away.
+    pub fn back_up(&mut self, file: &Path, key: &str, hash: &str, verbose: bool) -> anyhow::Result<PathBuf> {
+        self.store(file, key, hash, false, verbose)
+    }
+
+    /// Back up `file` like [`BackupStore::back_up`], but copy it rather than
+    /// move it if it can't be linked, so that it is left in place.
+    pub fn back_up_copy(&mut self, file: &Path, key: &str, hash: &str, verbose: bool) -> anyhow::Result<PathBuf> {
+        self.store(file, key, hash, true, verbose)
+    }
+
+    fn store(&mut self, file: &Path, key: &str, hash: &str, keep: bool, verbose: bool) -> anyhow::Result<PathBuf> {
+        let blob = self.blob_path(hash);
+        self.index.generations
+            .entry(self.generation.clone())
+            .or_default()
+            .insert(key.to_owned(), hash.to_owned());
+
+        if blob.is_file() {
+            if verbose {
+                eprintln!("{} is already backed up as {}", file.to_string_lossy(), blob.to_string_lossy());
+            }
+            return Ok(blob);
+        }
+
+        let blob_parent = blob.parent().unwrap();
+        std::fs::create_dir_all(blob_parent)
+            .with_context(|| format!("Failed to make backup directory {}", blob_parent.to_string_lossy()))?;
+        if verbose {
+            eprintln!("Backing up {} to {}", file.to_string_lossy(), blob.to_string_lossy());
+        }
+
+        if let Some(link) = self.link.filter(|_| !self.link_failed.get()) {
+            let linked = match link {
+                BackupLink::Reflink => reflink_copy::reflink(file, &blob),
+                BackupLink::Hard => std::fs::hard_link(file, &blob),
+            };
+            match linked {
+                Ok(()) => return Ok(blob),
+                Err(e) => {
+                    eprintln!(
+                        "Unable to link {} to {} ({}); {} originals to the backup instead",
+                        blob.to_string_lossy(),
+                        file.to_string_lossy(),
+                        e,
+                        if keep { "copying" } else { "moving" },
+                    );
+                    self.link_failed.set(true);
+                },
+            }
+        }
+
+        if keep {
+            copy_verified(file, &blob, hash)?;
+            return Ok(blob);
+        }
+        match std::fs::rename(file, &blob) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                copy_verified(file, &blob, hash)?;
+                std::fs::remove_file(file)
+                    .with_context(|| format!("Failed to remove {} after backing it up", file.to_string_lossy()))?;
+            },
+            Err(e) => return Err(e).with_context(|| format!(
+                "Failed to move the target ICE file {} to the backup path {}",
+                file.to_string_lossy(),
+                blob.to_string_lossy(),
+            )),
+        }
+        Ok(blob)
+    }
+
+    /// Forget all but the newest `keep` generations, then remove the blobs no
+    /// longer needed.
+    pub fn prune(&mut self, keep: usize, state: &InstallState, verbose: bool) -> anyhow::Result<()> {
+        let count = self.index.generations.len();
+        if count > keep {
+            let old: Vec<String> = self.index.generations.keys().take(count - keep).cloned().collect();
+            for generation in old {
+                if verbose {
+                    eprintln!("Removing backup generation {}", generation);
+                }
+                self.index.generations.remove(&generation);
+            }
+            self.save()?;
+        }
+        self.gc(state, verbose)?;
+        Ok(())
+    }
+
+    /// Remove every blob not in any generation of the index. Blobs holding the
+    /// original of a currently patched ICE file are its only backup and are
+    /// always kept. Returns the number of blobs and bytes removed.
+    pub fn gc(&self, state: &InstallState, verbose: bool) -> anyhow::Result<(usize, u64)> {
+        let mut referenced: HashSet<PathBuf> = self.index.generations
+            .values()
+            .flat_map(|g| g.values())
+            .map(|h| self.blob_path(h))
+            .collect();
+        referenced.extend(state.ices.values().filter_map(|r| r.backup.clone()));
+
+        let mut removed = (0, 0);
+        let blobs_dir = self.root.join("blobs");
+        if !blobs_dir.is_dir() {
+            return Ok(removed);
+        }
+        for prefix_dir in read_dir_paths(&blobs_dir)? {
+            if !prefix_dir.is_dir() {
+                continue;
+            }
+            for blob in read_dir_paths(&prefix_dir)? {
+                if referenced.contains(&blob) {
+                    continue;
+                }
+                let len = blob.metadata().map(|m| m.len()).unwrap_or(0);
+                if verbose {
+                    eprintln!("Removing unreferenced backup {}", blob.to_string_lossy());
+                }
+                std::fs::remove_file(&blob)
+                    .with_context(|| format!("Failed to remove backup {}", blob.to_string_lossy()))?;
+                removed.0 += 1;
+                removed.1 += len;
+            }
+            // only succeeds once the directory is empty
+            let _e = std::fs::remove_dir(&prefix_dir);
+        }
+        Ok(removed)
+    }
+}
+
+fn read_dir_paths(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let read_dir = dir.read_dir()
+        .with_context(|| format!("Failed to read backup directory {}", dir.to_string_lossy()))?;
+    let mut paths = Vec::new();
+    for entry in read_dir {
+        let entry = entry
+            .with_context(|| format!("Failed to index backup directory {}", dir.to_string_lossy()))?;
+        paths.push(entry.path());
+    }
+    Ok(paths)
+}
+
+/// Copy `from` to `to`, checking that the copy hashes to `hash` and is on disk
+/// before it is moved into place.
+fn copy_verified(from: &Path, to: &Path, hash: &str) -> anyhow::Result<()> {
+    let mut partial_name = to.file_name().unwrap().to_os_string();
+    partial_name.push(".partial");
+    let partial = to.with_file_name(partial_name);
+
+    let mut source = File::open(from)
+        .with_context(|| format!("Failed to open {}", from.to_string_lossy()))?;
+    let dest = File::create(&partial)
+        .with_context(|| format!("Failed to create {}", partial.to_string_lossy()))?;
+    let mut sink = HashWriter::new(dest);
+    io::copy(&mut source, &mut sink)
+        .with_context(|| format!("Failed to copy {} to {}", from.to_string_lossy(), partial.to_string_lossy()))?;
+    let (dest, copied_hash) = sink.finish();
+    dest.sync_all()
+        .with_context(|| format!("Failed to flush {}", partial.to_string_lossy()))?;
+    drop(dest);
+
+    if copied_hash != hash {
+        // best effort; the mismatch is the error worth reporting
+        let _e = std::fs::remove_file(&partial);
+        bail!("Copy of {} to {} does not match the original", from.to_string_lossy(), partial.to_string_lossy());
+    }
+    std::fs::rename(&partial, to)
+        .with_context(|| format!("Failed to move {} into place", partial.to_string_lossy()))
+}
+
+/// Name of a generation started at `secs` since the Unix epoch, as
+/// `YYYYMMDD-HHMMSS` in UTC so that names sort by age.
+fn generation_name(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = state::utc(secs);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, hour, minute, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::state::IceRecord;
+
+    /// Back up a file holding `contents` as `key` in a new generation of the
+    /// store in `dir`, returning the generation's name and the blob.
+    fn back_up_generation(dir: &Path, key: &str, contents: &[u8]) -> (String, PathBuf) {
+        let mut store = BackupStore::new(&dir.join("backup"), None).unwrap();
+        let file = dir.join("original");
+        std::fs::write(&file, contents).unwrap();
+        let blob = store.back_up(&file, key, &hash::hash_bytes(contents), false).unwrap();
+        store.save().unwrap();
+        (store.generation, blob)
+    }
+
+    #[test]
+    fn keep_leaves_the_newest_generations() {
+        let dir = std::env::temp_dir().join(format!("pso2-modpatcher-test-{}-prune", std::process::id()));
+        let _e = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // enough runs within the same second to need two digit counters
+        let made: Vec<(String, PathBuf)> = (0..12u8)
+            .map(|i| back_up_generation(&dir, "win32/aaa", &[i]))
+            .collect();
+
+        let mut store = BackupStore::new(&dir.join("backup"), None).unwrap();
+        let names: Vec<&str> = made.iter().map(|(g, _)| g.as_str()).collect();
+        assert_eq!(store.generations().collect::<Vec<_>>(), names);
+        store.prune(3, &InstallState::default(), false).unwrap();
+
+        assert_eq!(store.generations().collect::<Vec<_>>(), &names[9..]);
+        let store = BackupStore::open_existing(&dir.join("backup")).unwrap().unwrap();
+        assert_eq!(store.generations().collect::<Vec<_>>(), &names[9..]);
+        for (i, (_, blob)) in made.iter().enumerate() {
+            assert_eq!(blob.is_file(), i >= 9, "blob of generation {}", i);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gc_keeps_backups_of_patched_files() {
+        let dir = std::env::temp_dir().join(format!("pso2-modpatcher-test-{}-gc", std::process::id()));
+        let _e = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (_, patched) = back_up_generation(&dir, "win32/aaa", b"patched original");
+        let (_, unpatched) = back_up_generation(&dir, "win32/bbb", b"restored original");
+        let (_, listed) = back_up_generation(&dir, "win32/ccc", b"listed original");
+
+        // forget the first two generations, leaving the first blob referenced
+        // only by the install state
+        let mut store = BackupStore::new(&dir.join("backup"), None).unwrap();
+        let old: Vec<String> = store.generations().take(2).map(|g| g.to_owned()).collect();
+        for generation in old {
+            store.index.generations.remove(&generation);
+        }
+        let mut state = InstallState::default();
+        state.ices.insert("win32/aaa".to_owned(), IceRecord {
+            sources: Vec::new(),
+            filters: BTreeMap::new(),
+            original_hash: Some(hash::hash_bytes(b"patched original")),
+            patched_hash: hash::hash_bytes(b"patched"),
+            backup: Some(patched.clone()),
+            patched_at: 0,
+        });
+
+        let (count, bytes) = store.gc(&state, false).unwrap();
+        assert_eq!((count, bytes), (1, b"restored original".len() as u64));
+        assert!(patched.is_file());
+        assert!(!unpatched.exists());
+        assert!(listed.is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
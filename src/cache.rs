@@ -0,0 +1,141 @@
+//! Cache of patched ICE archives, so that patching the same original with the
+//! same files again copies the earlier output instead of rebuilding it.
+//!
+//! Each output is stored as `<key>.ice` next to a `<key>.json` describing the
+//! inputs it was built from, where the key is a hash of the original archive
+//! and of every patch file. The description is written last, so an output
+//! without one is treated as missing.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::{self, HashWriter};
+use crate::ice;
+use crate::rename::RenameRules;
+use crate::source::GroupFiles;
+
+pub struct PatchCache {
+    dir: PathBuf,
+}
+
+/// What a cached output was built from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Key of the patched archive within its data directory.
+    pub target: String,
+    pub original_hash: String,
+    pub inputs: Vec<CacheInput>,
+    pub output_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheInput {
+    pub group: usize,
+    pub name: String,
+    pub hash: String,
+}
+
+impl PatchCache {
+    pub fn open(dir: &Path) -> anyhow::Result<PatchCache> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to make cache directory {}", dir.to_string_lossy()))?;
+        Ok(PatchCache { dir: dir.to_path_buf() })
+    }
+
+    /// Hash the files of a patch, returning them along with the cache key for
+    /// applying them and the rename rules `rename` to an archive with hash
+    /// `original_hash`.
+    pub fn key(&self, original_hash: &str, files: &[&GroupFiles; 2], rename: Option<&RenameRules>) -> anyhow::Result<(String, Vec<CacheInput>)> {
+        let mut inputs = Vec::new();
+        for &group in ice::GROUPS.iter() {
+            let group_num = ice::group_index(group) + 1;
+            for (_, file) in files[ice::group_index(group)].iter() {
+                let file_hash = hash::hash_file(&file.path)
+                    .with_context(|| format!("Failed to hash patch file {}", file.path.to_string_lossy()))?;
+                inputs.push(CacheInput {
+                    group: group_num,
+                    name: file.name.clone(),
+                    hash: file_hash,
+                });
+            }
+        }
+
+        // the version is part of the key, as a newer patcher may write
+        // different output for the same inputs
+        let mut description = format!("{} {}\n{}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), original_hash);
+        for input in inputs.iter() {
+            description.push_str(&format!("{} {} {}\n", input.group, input.name, input.hash));
+        }
+        if let Some(rename) = rename {
+            description.push_str(&rename.describe());
+        }
+        Ok((hash::hash_bytes(description.as_bytes()), inputs))
+    }
+
+    /// Copy the cached output for `key` to `dest`, returning its hash, or
+    /// `None` if there is no usable cached output.
+    pub fn fetch(&self, key: &str, dest: &Path) -> anyhow::Result<Option<String>> {
+        let entry = match self.read_entry(key) {
+            Ok(Some(e)) => e,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                eprintln!("Warning: ignoring cached output {}: {:#}", key, e);
+                return Ok(None);
+            },
+        };
+
+        let cached_path = self.dir.join(format!("{}.ice", key));
+        let mut cached = File::open(&cached_path)
+            .with_context(|| format!("Failed to open cached output {}", cached_path.to_string_lossy()))?;
+        let dest_file = File::create(dest)
+            .with_context(|| format!("Failed to create {}", dest.to_string_lossy()))?;
+        let mut sink = HashWriter::new(dest_file);
+        io::copy(&mut cached, &mut sink)
+            .with_context(|| format!(
+                "Failed to copy cached output {} to {}",
+                cached_path.to_string_lossy(),
+                dest.to_string_lossy(),
+            ))?;
+        let (_, output_hash) = sink.finish();
+
+        if output_hash != entry.output_hash {
+            eprintln!("Warning: cached output {} is corrupt; rebuilding", cached_path.to_string_lossy());
+            std::fs::remove_file(dest)
+                .with_context(|| format!("Failed to remove {}", dest.to_string_lossy()))?;
+            return Ok(None);
+        }
+        Ok(Some(output_hash))
+    }
+
+    fn read_entry(&self, key: &str) -> anyhow::Result<Option<CacheEntry>> {
+        let entry_path = self.dir.join(format!("{}.json", key));
+        if !entry_path.is_file() || !self.dir.join(format!("{}.ice", key)).is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read(&entry_path)
+            .with_context(|| format!("Failed to read {}", entry_path.to_string_lossy()))?;
+        let entry = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {}", entry_path.to_string_lossy()))?;
+        Ok(Some(entry))
+    }
+
+    /// Store a copy of `output` as the result for `key`.
+    pub fn store(&self, key: &str, output: &Path, entry: &CacheEntry) -> anyhow::Result<()> {
+        let cached_path = self.dir.join(format!("{}.ice", key));
+        let partial_path = self.dir.join(format!("{}.ice.partial", key));
+        std::fs::copy(output, &partial_path)
+            .with_context(|| format!("Failed to copy {} into the cache", output.to_string_lossy()))?;
+        std::fs::rename(&partial_path, &cached_path)
+            .with_context(|| format!("Failed to move {} into place", cached_path.to_string_lossy()))?;
+
+        let entry_path = self.dir.join(format!("{}.json", key));
+        let contents = serde_json::to_vec_pretty(entry)
+            .with_context(|| "Failed to serialize cache entry")?;
+        std::fs::write(&entry_path, contents)
+            .with_context(|| format!("Failed to write {}", entry_path.to_string_lossy()))
+    }
+}
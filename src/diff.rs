@@ -0,0 +1,146 @@
+//! Entry-by-entry comparison of two ICE archives.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::hash;
+use crate::ice::{self, IceContents, IceEntry};
+
+#[derive(Debug, Serialize)]
+pub struct IceDiff {
+    pub header: Vec<HeaderChange>,
+    pub groups: Vec<GroupDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeaderChange {
+    pub field: &'static str,
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupDiff {
+    pub group: u32,
+    pub added: Vec<EntryInfo>,
+    pub removed: Vec<EntryInfo>,
+    pub changed: Vec<EntryChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    pub name: String,
+    pub ext: String,
+    pub size: usize,
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryChange {
+    pub name: String,
+    pub a: EntryInfo,
+    pub b: EntryInfo,
+}
+
+impl IceDiff {
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty()
+            && self.groups.iter().all(|g| g.added.is_empty() && g.removed.is_empty() && g.changed.is_empty())
+    }
+}
+
+impl EntryInfo {
+    fn new(entry: &IceEntry) -> EntryInfo {
+        EntryInfo {
+            name: entry.name.clone(),
+            ext: entry.ext.clone(),
+            size: entry.data.len(),
+            hash: hash::hash_bytes(&entry.data),
+        }
+    }
+}
+
+/// Compare the headers and entries of two ICE archives. Entries are matched by
+/// name within each group.
+pub fn diff_ice(a: &IceContents, b: &IceContents) -> IceDiff {
+    let mut header = Vec::new();
+    let mut check = |field, va: String, vb: String| {
+        if va != vb {
+            header.push(HeaderChange { field, a: va, b: vb });
+        }
+    };
+    check("version", a.version.to_string(), b.version.to_string());
+    check("encrypted", a.encrypted.to_string(), b.encrypted.to_string());
+    check("oodle", a.oodle.to_string(), b.oodle.to_string());
+    check("group 1 compressed", a.compressed[0].to_string(), b.compressed[0].to_string());
+    check("group 2 compressed", a.compressed[1].to_string(), b.compressed[1].to_string());
+
+    let groups = ice::GROUPS
+        .iter()
+        .enumerate()
+        .map(|(i, &group)| diff_group(i as u32 + 1, a.group(group), b.group(group)))
+        .collect();
+
+    IceDiff { header, groups }
+}
+
+fn diff_group(group: u32, a: &[IceEntry], b: &[IceEntry]) -> GroupDiff {
+    let a_by_name: HashMap<&str, &IceEntry> = a.iter().map(|e| (e.name.as_str(), e)).collect();
+    let b_by_name: HashMap<&str, &IceEntry> = b.iter().map(|e| (e.name.as_str(), e)).collect();
+
+    let mut diff = GroupDiff {
+        group,
+        added: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+    };
+    for entry in a {
+        match b_by_name.get(entry.name.as_str()) {
+            None => diff.removed.push(EntryInfo::new(entry)),
+            Some(other) if other.data != entry.data || other.ext != entry.ext => {
+                diff.changed.push(EntryChange {
+                    name: entry.name.clone(),
+                    a: EntryInfo::new(entry),
+                    b: EntryInfo::new(other),
+                });
+            },
+            Some(_) => {},
+        }
+    }
+    for entry in b {
+        if !a_by_name.contains_key(entry.name.as_str()) {
+            diff.added.push(EntryInfo::new(entry));
+        }
+    }
+    diff
+}
+
+/// Print a diff in a human-readable form.
+pub fn print_diff(diff: &IceDiff) {
+    if diff.is_empty() {
+        println!("No differences");
+        return;
+    }
+    for change in diff.header.iter() {
+        println!("{}: {} -> {}", change.field, change.a, change.b);
+    }
+    for group in diff.groups.iter() {
+        if group.added.is_empty() && group.removed.is_empty() && group.changed.is_empty() {
+            continue;
+        }
+        println!("group {}:", group.group);
+        for e in group.removed.iter() {
+            println!("  - {} ({} bytes, {})", e.name, e.size, e.hash);
+        }
+        for e in group.added.iter() {
+            println!("  + {} ({} bytes, {})", e.name, e.size, e.hash);
+        }
+        for c in group.changed.iter() {
+            println!("  ~ {} ({} bytes, {} -> {} bytes, {})", c.name, c.a.size, c.a.hash, c.b.size, c.b.hash);
+            if c.a.ext != c.b.ext {
+                println!("    extension {} -> {}", c.a.ext, c.b.ext);
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Replacing entries in every ICE file of a data directory that holds them.
+//!
+//! The `everywhere` section of a manifest is expanded, using the entry index of
+//! the data directory, into an `_ice` directory for each ICE file holding an
+//! entry it names. The directories are kept in the data directory, so that the
+//! ICE files they patch can be reapplied and verified like any others.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::hash;
+use crate::index::EntryIndex;
+use crate::state::InstallState;
+
+/// Directory within the data directory to expand the `everywhere` sections of
+/// manifests into.
+const EVERYWHERE_DIR_NAME: &str = ".modpatcher-everywhere";
+
+/// A patch directory expanded from an `everywhere` section, removed again when
+/// dropped if it was only expanded for a dry run.
+pub struct Expanded {
+    dir: PathBuf,
+    temporary: bool,
+}
+
+impl Expanded {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for Expanded {
+    fn drop(&mut self) {
+        if self.temporary {
+            // best effort; it is in the temporary directory either way
+            let _e = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Bring the entry index of `datadir`, whose backups are in `backup_dir`, up
+/// to date, then write a patch
+/// directory with an `_ice` directory for every ICE file holding an entry of
+/// `everywhere`, replacing each such entry with its file from `patch_dir`.
+/// Returns the patch directory, or `None` if there is nothing to replace.
+///
+/// `_ice` directories left from an earlier expansion are removed unless a
+/// record of `state` still names them as a source. For a dry run, the index is
+/// not saved and the patch directory is written to the temporary directory, so
+/// that nothing in `datadir` changes.
+pub fn expand(
+    patch_dir: &Path,
+    everywhere: &BTreeMap<String, PathBuf>,
+    datadir: &Path,
+    backup_dir: &Path,
+    state: &InstallState,
+    dry_run: bool,
+    verbose: bool,
+) -> anyhow::Result<Option<Expanded>> {
+    if everywhere.is_empty() {
+        return Ok(None);
+    }
+
+    eprintln!("Updating the index of {} to find entries to replace", datadir.to_string_lossy());
+    let previous = EntryIndex::load(datadir).unwrap_or_else(|e| {
+        eprintln!("Warning: rebuilding the index from scratch: {:#}", e);
+        None
+    });
+    let (index, _) = EntryIndex::build(datadir, backup_dir, previous, verbose)?;
+    if !dry_run {
+        index.save(datadir)?;
+    }
+
+    // one directory per package, so that packages can be installed together
+    let package = std::fs::canonicalize(patch_dir)
+        .with_context(|| format!("Failed to resolve patch path {}", patch_dir.to_string_lossy()))?;
+    let package_hash = hash::hash_bytes(package.to_string_lossy().as_bytes());
+    let expanded = if dry_run {
+        let dir = std::env::temp_dir().join(format!("pso2-modpatcher-{}-everywhere-{}", std::process::id(), &package_hash[..16]));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove {}", dir.to_string_lossy()))?;
+        }
+        Expanded { dir, temporary: true }
+    } else {
+        Expanded { dir: datadir.join(EVERYWHERE_DIR_NAME).join(&package_hash[..16]), temporary: false }
+    };
+    let out = expanded.dir();
+
+    let mut touched = 0;
+    // `_ice` directories written, each cleared of earlier files when first
+    // written to
+    let mut written = HashSet::new();
+    for (name, file) in everywhere.iter() {
+        let file = patch_dir.join(file);
+        let mut keys = Vec::new();
+        for (key, entry) in index.entries().filter(|(_, e)| e.name.eq_ignore_ascii_case(name)) {
+            let ice_dir = out.join(format!("{}_ice", key));
+            if written.insert(ice_dir.clone()) && ice_dir.exists() {
+                std::fs::remove_dir_all(&ice_dir)
+                    .with_context(|| format!("Failed to remove {}", ice_dir.to_string_lossy()))?;
+            }
+            // named after the entry, so that its case is kept
+            let dest_dir = ice_dir.join(entry.group.to_string());
+            std::fs::create_dir_all(&dest_dir)
+                .with_context(|| format!("Failed to make directory {}", dest_dir.to_string_lossy()))?;
+            let dest = dest_dir.join(&entry.name);
+            std::fs::copy(&file, &dest)
+                .with_context(|| format!("Failed to copy {} to {}", file.to_string_lossy(), dest.to_string_lossy()))?;
+            if keys.last() != Some(&key) {
+                keys.push(key);
+            }
+        }
+
+        if keys.is_empty() {
+            eprintln!("Warning: no ICE file holds an entry named {}", name);
+            continue;
+        }
+        eprintln!("Replacing {} in {} ICE file(s):", name, keys.len());
+        for key in keys.iter() {
+            eprintln!("  {}", key);
+        }
+        touched += keys.len();
+    }
+
+    if !dry_run && out.is_dir() {
+        let in_use: HashSet<&Path> = state.ices
+            .values()
+            .flat_map(|r| r.sources.iter().map(|s| s.as_path()))
+            .collect();
+        remove_stale(out, &written, &in_use)?;
+    }
+
+    if touched == 0 {
+        return Ok(None);
+    }
+    Ok(Some(expanded))
+}
+
+/// Remove the `_ice` directories under `dir` that were not just written and
+/// are not the source of any patched ICE file, along with directories left
+/// empty.
+fn remove_stale(dir: &Path, written: &HashSet<PathBuf>, in_use: &HashSet<&Path>) -> anyhow::Result<()> {
+    let read_dir = dir.read_dir()
+        .with_context(|| format!("Unable to read dir {}", dir.to_string_lossy()))?;
+    for entry in read_dir {
+        let path = entry
+            .with_context(|| format!("Unable to index file while reading dir {}", dir.to_string_lossy()))?
+            .path();
+        if !path.is_dir() {
+            continue;
+        }
+        if !path.to_string_lossy().ends_with("_ice") {
+            remove_stale(&path, written, in_use)?;
+            // best effort; only succeeds if it is now empty
+            let _e = std::fs::remove_dir(&path);
+            continue;
+        }
+        let source = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if !written.contains(&path) && !in_use.contains(source.as_path()) {
+            std::fs::remove_dir_all(&path)
+                .with_context(|| format!("Failed to remove {}", path.to_string_lossy()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unused_ice_directories_are_stale() {
+        let dir = std::env::temp_dir().join(format!("pso2-modpatcher-test-{}-stale", std::process::id()));
+        let _e = std::fs::remove_dir_all(&dir);
+        for name in ["written_ice", "in_use_ice", "stale_ice"].iter() {
+            std::fs::create_dir_all(dir.join("win32").join(name).join("1")).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("win32_na").join("gone_ice")).unwrap();
+
+        let written: HashSet<PathBuf> = vec![dir.join("win32").join("written_ice")].into_iter().collect();
+        let in_use_dir = std::fs::canonicalize(dir.join("win32").join("in_use_ice")).unwrap();
+        let in_use: HashSet<&Path> = vec![in_use_dir.as_path()].into_iter().collect();
+        remove_stale(&dir, &written, &in_use).unwrap();
+
+        assert!(dir.join("win32").join("written_ice").is_dir());
+        assert!(dir.join("win32").join("in_use_ice").is_dir());
+        assert!(!dir.join("win32").join("stale_ice").exists());
+        assert!(!dir.join("win32_na").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! Selecting part of a patch with include and exclude globs.
+//!
+//! Globs are matched, without regard to case, against the path of each patch
+//! file as `<ice path>/<group>/<entry name>`, where the ICE path is relative to
+//! the data directory, e.g. `win32/abcd.../1/file.dds`. A glob also matches a
+//! file if it matches the leading part of that path, so `win32/abcd...` selects
+//! every file for that ICE.
+
+use std::convert::TryFrom;
+
+use anyhow::Context;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+/// The globs a filter was built from, as recorded in the install state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterPatterns {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "FilterPatterns", into = "FilterPatterns")]
+pub struct PatchFilter {
+    patterns: FilterPatterns,
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl PatchFilter {
+    /// Build a filter from include and exclude globs, or `None` if there are
+    /// neither.
+    pub fn new(include: &[String], exclude: &[String]) -> anyhow::Result<Option<PatchFilter>> {
+        if include.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+        PatchFilter::try_from(FilterPatterns {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }).map(Some)
+    }
+
+    /// Whether the file providing entry `name` in group `group` (1 or 2) of
+    /// the ICE file at `ice_key` should be applied.
+    pub fn includes(&self, ice_key: &str, group: usize, name: &str) -> bool {
+        let group_path = format!("{}/{}", ice_key, group);
+        let entry_path = format!("{}/{}", group_path, name);
+        let candidates = [ice_key, group_path.as_str(), entry_path.as_str()];
+
+        let included = self.patterns.include.is_empty()
+            || candidates.iter().any(|c| self.include.is_match(c));
+        let excluded = candidates.iter().any(|c| self.exclude.is_match(c));
+        included && !excluded
+    }
+}
+
+impl TryFrom<FilterPatterns> for PatchFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(patterns: FilterPatterns) -> anyhow::Result<PatchFilter> {
+        Ok(PatchFilter {
+            include: build_set(&patterns.include)?,
+            exclude: build_set(&patterns.exclude)?,
+            patterns,
+        })
+    }
+}
+
+impl From<PatchFilter> for FilterPatterns {
+    fn from(filter: PatchFilter) -> FilterPatterns {
+        filter.patterns
+    }
+}
+
+fn build_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid glob {:?}", pattern))?;
+        builder.add(glob);
+    }
+    builder.build().with_context(|| "Failed to compile globs")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ICE: &str = "win32/0123456789abcdef0123456789abcdef";
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PatchFilter {
+        let include: Vec<String> = include.iter().map(|s| s.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|s| s.to_string()).collect();
+        PatchFilter::new(&include, &exclude).unwrap().unwrap()
+    }
+
+    #[test]
+    fn no_globs_is_no_filter() {
+        assert!(PatchFilter::new(&[], &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn include_matches_any_leading_part() {
+        let by_ice = filter(&[ICE], &[]);
+        assert!(by_ice.includes(ICE, 1, "a.dds"));
+        assert!(by_ice.includes(ICE, 2, "b.aqp"));
+        assert!(!by_ice.includes("win32/ffff", 1, "a.dds"));
+
+        let by_group = filter(&[&format!("{}/2", ICE)], &[]);
+        assert!(!by_group.includes(ICE, 1, "a.dds"));
+        assert!(by_group.includes(ICE, 2, "a.dds"));
+    }
+
+    #[test]
+    fn globs_ignore_case_and_star_crosses_slashes() {
+        let f = filter(&["*/1/*.DDS"], &[]);
+        assert!(f.includes(ICE, 1, "tex.dds"));
+        assert!(!f.includes(ICE, 2, "tex.dds"));
+        assert!(!f.includes(ICE, 1, "model.aqp"));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let f = filter(&["win32/*"], &["*.aqp"]);
+        assert!(f.includes(ICE, 1, "tex.dds"));
+        assert!(!f.includes(ICE, 1, "model.aqp"));
+        assert!(!f.includes("win32reboot/01/23", 1, "tex.dds"));
+    }
+
+    #[test]
+    fn exclude_alone_keeps_everything_else() {
+        let f = filter(&[], &[ICE]);
+        assert!(!f.includes(ICE, 1, "tex.dds"));
+        assert!(f.includes("win32/ffff", 1, "tex.dds"));
+    }
+
+    #[test]
+    fn invalid_globs_are_errors() {
+        assert!(PatchFilter::new(&["[".to_owned()], &[]).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Content hashing used to recognise files this tool has written.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Hex-encoded SHA-256 of a byte slice.
+pub fn hash_bytes(data: &[u8]) -> String {
+    hex(Sha256::digest(data).as_slice())
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex(hasher.finalize().as_slice()))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A writer that hashes everything passed through it to the inner writer.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Consume the writer, returning the inner writer and the hex-encoded hash
+    /// of everything written.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex(self.hasher.finalize().as_slice()))
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
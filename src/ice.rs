@@ -0,0 +1,152 @@
+//! Owned, fully unpacked views of ICE archives for inspection.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use ages_ice_archive::{Group, IceArchive, IceGroupIter};
+use anyhow::{bail, Context};
+
+pub const GROUPS: [Group; 2] = [Group::Group1, Group::Group2];
+
+/// Size of the extension field in an entry header. Extensions are
+/// NUL-padded, so may use the whole field.
+pub const EXT_FIELD_LEN: usize = 4;
+
+/// Longest name, including the terminating NUL, we give a new entry. Entry
+/// headers store names of any length, so this is only a precaution against
+/// names the client may not expect, and entries an archive already holds are
+/// never refused for their length.
+pub const NEW_NAME_LEN: usize = 0x40;
+
+/// A file entry in an ICE group.
+pub struct IceEntry {
+    pub name: String,
+    pub ext: String,
+    pub data: Vec<u8>,
+}
+
+/// The header properties and entries of an ICE archive.
+pub struct IceContents {
+    pub version: u32,
+    pub encrypted: bool,
+    pub oodle: bool,
+    pub compressed: [bool; 2],
+    pub groups: [Vec<IceEntry>; 2],
+}
+
+impl IceContents {
+    pub fn group(&self, group: Group) -> &[IceEntry] {
+        &self.groups[group_index(group)]
+    }
+}
+
+pub fn group_index(group: Group) -> usize {
+    match group {
+        Group::Group1 => 0,
+        Group::Group2 => 1,
+    }
+}
+
+/// Check that a file name can be stored as an entry name, returning a
+/// description of the problem if not.
+pub fn check_entry_name(name: &str) -> Result<(), String> {
+    if !name.is_ascii() {
+        return Err("name is not ASCII".to_owned());
+    }
+    if name.contains('\0') {
+        return Err("name contains a NUL".to_owned());
+    }
+    let ext = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => ext,
+        _ => return Err("name has no extension".to_owned()),
+    };
+    if ext.len() > EXT_FIELD_LEN {
+        return Err(format!("extension .{} is longer than {} characters", ext, EXT_FIELD_LEN));
+    }
+    Ok(())
+}
+
+/// Check that a name can be given to a new entry, which is also held to
+/// [`NEW_NAME_LEN`].
+pub fn check_new_entry_name(name: &str) -> Result<(), String> {
+    check_entry_name(name)?;
+    if name.len() + 1 > NEW_NAME_LEN {
+        return Err(format!("name is {} characters; new entries may have at most {}", name.len(), NEW_NAME_LEN - 1));
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` starts with the ICE magic.
+pub fn is_ice(path: &Path) -> anyhow::Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+    match file.read_exact(&mut magic) {
+        Ok(_) => Ok(&magic == b"ICE\0"),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Load an ICE archive and unpack both of its groups.
+pub fn read_ice(path: &Path) -> anyhow::Result<IceContents> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open ICE file \"{}\"", path.to_string_lossy()))?;
+    let ia = IceArchive::load(file)
+        .with_context(|| format!("Failed to load \"{}\" as an ICE", path.to_string_lossy()))?;
+
+    let mut groups: [Vec<IceEntry>; 2] = Default::default();
+    for &group in GROUPS.iter() {
+        let data = ia.decompress_group(group)
+            .with_context(|| format!("Failed to unpack {} of {}", group, path.to_string_lossy()))?;
+        let iter = match IceGroupIter::new(&data, ia.group_count(group)) {
+            Ok(i) => i,
+            Err(_) => bail!("Unable to iterate over {} files in {}", group, path.to_string_lossy()),
+        };
+        for file in iter {
+            let name = file.name()
+                .with_context(|| format!("Entry name in {} of {} is not valid ASCII", group, path.to_string_lossy()))?;
+            let ext = file.ext()
+                .with_context(|| format!("Entry extension in {} of {} is not valid ASCII", group, path.to_string_lossy()))?;
+            groups[group_index(group)].push(IceEntry {
+                name: name.to_owned(),
+                ext: ext.to_owned(),
+                data: file.data().to_vec(),
+            });
+        }
+    }
+
+    Ok(IceContents {
+        version: ia.version(),
+        encrypted: ia.is_encrypted(),
+        oodle: ia.is_oodle(),
+        compressed: [ia.is_compressed(Group::Group1), ia.is_compressed(Group::Group2)],
+        groups,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_names_need_an_ascii_name_and_short_extension() {
+        assert!(check_entry_name("tex.dds").is_ok());
+        assert!(check_entry_name("model.aqpx").is_ok());
+        assert!(check_entry_name("model.aqpxx").is_err());
+        assert!(check_entry_name("tex").is_err());
+        assert!(check_entry_name(".dds").is_err());
+        assert!(check_entry_name("tex.").is_err());
+        assert!(check_entry_name("t\u{e9}x.dds").is_err());
+        assert!(check_entry_name("t\0x.dds").is_err());
+    }
+
+    #[test]
+    fn only_new_entries_are_held_to_the_length_limit() {
+        let longest = format!("{}.dds", "a".repeat(NEW_NAME_LEN - 5));
+        let too_long = format!("a{}", longest);
+        assert!(check_new_entry_name(&longest).is_ok());
+        assert!(check_new_entry_name(&too_long).is_err());
+        assert!(check_entry_name(&too_long).is_ok());
+    }
+}
@@ -0,0 +1,231 @@
+//! Index of the entries of every ICE file in a data directory, for finding
+//! which ICE files hold an entry without reading them all.
+//!
+//! ICE files are read in parallel. Files whose size and modification time are
+//! unchanged since the last index keep their entries from it, so re-indexing
+//! after a game update only reads what the update replaced.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use globset::GlobBuilder;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::hash;
+use crate::ice;
+use crate::state;
+
+/// File name of the index within a data directory.
+const INDEX_FILE_NAME: &str = "modpatcher-index.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EntryIndex {
+    /// Seconds since the Unix epoch at which the index was built.
+    pub indexed_at: u64,
+    /// Indexed ICE files, keyed by their `/`-separated path relative to the
+    /// data directory.
+    pub ices: BTreeMap<String, IndexedIce>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedIce {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch, as a file may be
+    /// replaced within a second of being indexed.
+    pub modified: u64,
+    pub entries: Vec<IndexedEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedEntry {
+    /// Group of the entry, 1 or 2.
+    pub group: usize,
+    pub name: String,
+    pub ext: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Counts of what indexing did.
+#[derive(Debug, Default)]
+pub struct IndexStats {
+    pub read: usize,
+    pub reused: usize,
+    pub failed: usize,
+}
+
+/// A file of the data directory that may be an ICE file.
+struct Candidate {
+    key: String,
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+}
+
+impl EntryIndex {
+    /// Load the index of a data directory, if one has been built.
+    pub fn load(datadir: &Path) -> anyhow::Result<Option<EntryIndex>> {
+        let path = datadir.join(INDEX_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read(&path)
+            .with_context(|| format!("Failed to read index {}", path.to_string_lossy()))?;
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| format!("Failed to parse index {}", path.to_string_lossy()))
+    }
+
+    pub fn save(&self, datadir: &Path) -> anyhow::Result<()> {
+        // not pretty printed, as a full data directory has millions of entries
+        let contents = serde_json::to_vec(self)
+            .with_context(|| "Failed to serialize index")?;
+        state::write_atomic(&datadir.join(INDEX_FILE_NAME), &contents)
+            .with_context(|| "Failed to write index")
+    }
+
+    /// Index every ICE file in `datadir`, other than those in the backup
+    /// directory `backup_dir`, reusing the entries of files unchanged since
+    /// `previous` was built.
+    pub fn build(datadir: &Path, backup_dir: &Path, previous: Option<EntryIndex>, verbose: bool) -> anyhow::Result<(EntryIndex, IndexStats)> {
+        let mut candidates = Vec::new();
+        find_candidates(datadir, backup_dir, datadir, &mut candidates)?;
+        let previous = previous.unwrap_or_default();
+
+        let results: Vec<(String, anyhow::Result<Option<IndexedIce>>, bool)> = candidates
+            .into_par_iter()
+            .map(|candidate| {
+                if let Some(ice) = previous.ices.get(&candidate.key) {
+                    if ice.size == candidate.size && ice.modified == candidate.modified {
+                        return (candidate.key, Ok(Some(ice.clone())), true);
+                    }
+                }
+                let indexed = index_ice(&candidate)
+                    .with_context(|| format!("Failed to index {}", candidate.path.to_string_lossy()));
+                (candidate.key, indexed, false)
+            })
+            .collect();
+
+        let mut index = EntryIndex {
+            indexed_at: state::now(),
+            ices: BTreeMap::new(),
+        };
+        let mut stats = IndexStats::default();
+        for (key, indexed, reused) in results {
+            match indexed {
+                Ok(Some(ice)) => {
+                    if reused {
+                        stats.reused += 1;
+                    } else {
+                        stats.read += 1;
+                    }
+                    index.ices.insert(key, ice);
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    stats.failed += 1;
+                    if verbose {
+                        eprintln!("Warning: {:#}", e);
+                    }
+                },
+            }
+        }
+        Ok((index, stats))
+    }
+
+    /// Find the entries whose names match the glob `pattern`, without regard
+    /// to case, along with the key of the ICE file holding each.
+    pub fn find(&self, pattern: &str) -> anyhow::Result<Vec<(&str, &IndexedEntry)>> {
+        let matcher = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+        Ok(self.entries().filter(|(_, e)| matcher.is_match(&e.name)).collect())
+    }
+
+    /// Iterate over every indexed entry, along with the key of the ICE file
+    /// holding it.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &IndexedEntry)> {
+        self.ices
+            .iter()
+            .flat_map(|(key, ice)| ice.entries.iter().map(move |e| (key.as_str(), e)))
+    }
+}
+
+/// Whether `path`, found while walking a data directory, is one of our own
+/// files or directories, or the backup directory `backup_dir`, none of which
+/// hold ICE files of the game.
+pub fn is_own_path(path: &Path, backup_dir: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.starts_with(".modpatcher") || name.ends_with(".partial") {
+        return true;
+    }
+    // compared resolved, as the backup directory may be given by any path
+    path.is_dir() && match (std::fs::canonicalize(path), std::fs::canonicalize(backup_dir)) {
+        (Ok(path), Ok(backup_dir)) => path == backup_dir,
+        _ => false,
+    }
+}
+
+/// Collect every file under `dir` that may be an ICE file, skipping our own
+/// files and the backup directory `backup_dir`.
+fn find_candidates(datadir: &Path, backup_dir: &Path, dir: &Path, candidates: &mut Vec<Candidate>) -> anyhow::Result<()> {
+    let read_dir = dir.read_dir()
+        .with_context(|| format!("Failed to iterate over directory {}", dir.to_string_lossy()))?;
+    for file in read_dir {
+        let file = file
+            .with_context(|| format!("Failed to index a file in directory {}", dir.to_string_lossy()))?;
+        let path = file.path();
+        if is_own_path(&path, backup_dir) {
+            continue;
+        }
+        if path.is_dir() {
+            find_candidates(datadir, backup_dir, &path, candidates)?;
+            continue;
+        }
+
+        let metadata = file.metadata()
+            .with_context(|| format!("Failed to read metadata of {}", path.to_string_lossy()))?;
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        candidates.push(Candidate {
+            key: state::key(datadir, &path),
+            path,
+            size: metadata.len(),
+            modified,
+        });
+    }
+    Ok(())
+}
+
+/// Read the entries of a candidate, or `None` if it isn't an ICE file.
+fn index_ice(candidate: &Candidate) -> anyhow::Result<Option<IndexedIce>> {
+    if !ice::is_ice(&candidate.path)? {
+        return Ok(None);
+    }
+    let contents = ice::read_ice(&candidate.path)?;
+    let mut entries = Vec::new();
+    for &group in ice::GROUPS.iter() {
+        for entry in contents.group(group) {
+            entries.push(IndexedEntry {
+                group: ice::group_index(group) + 1,
+                name: entry.name.clone(),
+                ext: entry.ext.clone(),
+                size: entry.data.len() as u64,
+                hash: hash::hash_bytes(&entry.data),
+            });
+        }
+    }
+    Ok(Some(IndexedIce {
+        size: candidate.size,
+        modified: candidate.modified,
+        entries,
+    }))
+}
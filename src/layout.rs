@@ -0,0 +1,111 @@
+//! Knowledge of where the client keeps ICE archives inside a data directory.
+
+use std::path::PathBuf;
+
+/// Length of the hex-encoded MD5 hash ICE archives are named by.
+const ICE_HASH_LEN: usize = 32;
+
+/// Length of the hash prefix NGS uses as an intermediate directory.
+const REBOOT_PREFIX_LEN: usize = 2;
+
+/// The directory layouts ICE archives are stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// `win32/<hash>`, used by the base game.
+    Win32,
+    /// `win32reboot/<hash[..2]>/<hash[2..]>`, used by NGS.
+    Win32Reboot,
+}
+
+impl Layout {
+    /// Get the layout of a top-level data directory by its name, e.g. `win32`
+    /// or `win32reboot_na`.
+    pub fn from_dir_name(name: &str) -> Option<Layout> {
+        if name.starts_with("win32reboot") {
+            Some(Layout::Win32Reboot)
+        } else if name.starts_with("win32") {
+            Some(Layout::Win32)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `name` looks like the full hash name of an ICE archive.
+pub fn is_ice_hash(name: &str) -> bool {
+    name.len() == ICE_HASH_LEN && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Resolve the path of the ICE archive `ice_name`, relative to the data
+/// directory counterpart of the patch directory named `parent_name`.
+///
+/// In the NGS layout a full hash name may be given either directly in
+/// `win32reboot` or inside its prefix directory; both are placed at
+/// `<prefix>/<rest>`. Anything else is mirrored as-is.
+pub fn ice_path(layout: Option<Layout>, parent_name: &str, ice_name: &str) -> PathBuf {
+    if layout != Some(Layout::Win32Reboot) || !is_ice_hash(ice_name) {
+        return PathBuf::from(ice_name);
+    }
+
+    let (prefix, rest) = ice_name.split_at(REBOOT_PREFIX_LEN);
+    if Layout::from_dir_name(parent_name).is_some() {
+        [prefix, rest].iter().collect()
+    } else if parent_name.eq_ignore_ascii_case(prefix) {
+        PathBuf::from(rest)
+    } else {
+        PathBuf::from(ice_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn flat_reboot_hash_goes_in_its_prefix_directory() {
+        assert_eq!(
+            ice_path(Some(Layout::Win32Reboot), "win32reboot", HASH),
+            PathBuf::from("01").join("23456789abcdef0123456789abcdef"),
+        );
+        assert_eq!(
+            ice_path(Some(Layout::Win32Reboot), "win32reboot_na", HASH),
+            PathBuf::from("01").join("23456789abcdef0123456789abcdef"),
+        );
+    }
+
+    #[test]
+    fn reboot_hash_in_its_prefix_directory_drops_the_prefix() {
+        assert_eq!(
+            ice_path(Some(Layout::Win32Reboot), "01", HASH),
+            PathBuf::from("23456789abcdef0123456789abcdef"),
+        );
+        assert_eq!(
+            ice_path(Some(Layout::Win32Reboot), "01", &HASH.to_uppercase()),
+            PathBuf::from("23456789ABCDEF0123456789ABCDEF"),
+        );
+    }
+
+    #[test]
+    fn reboot_hash_in_another_directory_is_mirrored() {
+        assert_eq!(ice_path(Some(Layout::Win32Reboot), "ff", HASH), PathBuf::from(HASH));
+    }
+
+    #[test]
+    fn other_names_are_mirrored() {
+        assert_eq!(ice_path(Some(Layout::Win32Reboot), "win32reboot", "23456789abcdef"), PathBuf::from("23456789abcdef"));
+        assert_eq!(ice_path(Some(Layout::Win32Reboot), "win32reboot", &HASH.replace('0', "g")), PathBuf::from(HASH.replace('0', "g")));
+        assert_eq!(ice_path(Some(Layout::Win32), "win32", HASH), PathBuf::from(HASH));
+        assert_eq!(ice_path(None, "data", HASH), PathBuf::from(HASH));
+    }
+
+    #[test]
+    fn layouts_are_found_by_directory_name() {
+        assert_eq!(Layout::from_dir_name("win32"), Some(Layout::Win32));
+        assert_eq!(Layout::from_dir_name("win32_na"), Some(Layout::Win32));
+        assert_eq!(Layout::from_dir_name("win32reboot"), Some(Layout::Win32Reboot));
+        assert_eq!(Layout::from_dir_name("win32reboot_na"), Some(Layout::Win32Reboot));
+        assert_eq!(Layout::from_dir_name("data"), None);
+    }
+}
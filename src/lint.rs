@@ -0,0 +1,92 @@
+//! Finding files in a patch directory that patching would ignore or trip over.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::manifest::{Manifest, MANIFEST_FILE_NAME};
+use crate::rename::{RenameRules, RENAME_FILE_NAME};
+use crate::source::GroupFiles;
+use crate::swap::{Swap, SWAP_FILE_NAME};
+
+/// Walk a patch directory, returning a description of every file or
+/// directory that isn't where the patcher expects it.
+pub fn lint(patch_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut findings = Vec::new();
+    // loose files the manifest accounts for
+    let mut known = vec![patch_dir.join(MANIFEST_FILE_NAME)];
+    match Manifest::load(patch_dir) {
+        Ok(Some(manifest)) => known.extend(manifest.everywhere.values().map(|p| patch_dir.join(p))),
+        Ok(None) => {},
+        Err(e) => findings.push(format!("{:#}", e)),
+    }
+    lint_directory(patch_dir, &known, &mut findings)?;
+    Ok(findings)
+}
+
+fn lint_directory(dir: &Path, known: &[PathBuf], findings: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in sorted_entries(dir)? {
+        let name = entry.file_name().unwrap().to_string_lossy();
+        if !entry.is_dir() && known.contains(&entry) {
+            continue;
+        } else if !entry.is_dir() {
+            findings.push(format!("{}: ignored; files must be inside the 1 or 2 directory of an _ice directory", entry.to_string_lossy()));
+        } else if name == "backup" {
+            findings.push(format!("{}: directories named \"backup\" are not allowed", entry.to_string_lossy()));
+        } else if name.ends_with("_ice") {
+            lint_ice_directory(&entry, findings)?;
+        } else {
+            lint_directory(&entry, known, findings)?;
+        }
+    }
+    Ok(())
+}
+
+fn lint_ice_directory(dir: &Path, findings: &mut Vec<String>) -> anyhow::Result<()> {
+    let mut has_group = false;
+    for entry in sorted_entries(dir)? {
+        let name = entry.file_name().unwrap().to_string_lossy();
+        if !entry.is_dir() && name == SWAP_FILE_NAME {
+            has_group = true;
+            if let Err(e) = Swap::load(dir) {
+                findings.push(format!("{:#}", e));
+            }
+        } else if !entry.is_dir() && name == RENAME_FILE_NAME {
+            has_group = true;
+            if let Err(e) = RenameRules::load(dir) {
+                findings.push(format!("{:#}", e));
+            }
+        } else if entry.is_dir() && (name == "1" || name == "2") {
+            has_group = true;
+            lint_group_directory(&entry, findings)?;
+        } else if entry.is_dir() {
+            findings.push(format!("{}: ignored; an _ice directory may only contain 1 and 2 directories", entry.to_string_lossy()));
+        } else {
+            findings.push(format!("{}: ignored; files must be inside the 1 or 2 directory", entry.to_string_lossy()));
+        }
+    }
+    if !has_group {
+        findings.push(format!("{}: has no 1 or 2 directory, swap or rename rules to patch from", dir.to_string_lossy()));
+    }
+    Ok(())
+}
+
+fn lint_group_directory(dir: &Path, findings: &mut Vec<String>) -> anyhow::Result<()> {
+    if let Err(e) = GroupFiles::scan(dir) {
+        findings.push(format!("{:#}", e));
+    }
+    Ok(())
+}
+
+fn sorted_entries(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let read_dir = dir.read_dir()
+        .with_context(|| format!("Failed to iterate over patch directory {}", dir.to_string_lossy()))?;
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        let entry = entry
+            .with_context(|| format!("Failed to index a file in patch directory {}", dir.to_string_lossy()))?;
+        entries.push(entry.path());
+    }
+    entries.sort();
+    Ok(entries)
+}
@@ -1,15 +1,56 @@
+// the original patching code is kept as it was written
+#![allow(clippy::redundant_slicing, clippy::single_match)]
+
+mod backup;
+mod cache;
+mod diff;
+mod everywhere;
+mod filter;
+mod hash;
+mod ice;
+mod index;
+mod layout;
+mod lint;
+mod make_patch;
+mod manifest;
+mod profile;
+mod rename;
+mod source;
+mod state;
+mod status;
+mod swap;
+mod validate;
+mod verify;
+
 use ages_ice_archive::{Group, IceArchive, IceGroupIter, IceWriter};
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{atomic::{AtomicU64, Ordering}, mpsc};
+use std::sync::mpsc;
+#[cfg(windows)]
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{bail, Context};
 use ascii::{AsciiStr, AsciiString};
 use structopt::StructOpt;
 
+use crate::backup::{BackupLink, BackupStore};
+use crate::cache::{CacheEntry, PatchCache};
+use crate::everywhere::Expanded;
+use crate::hash::HashWriter;
+use crate::filter::PatchFilter;
+use crate::index::EntryIndex;
+use crate::layout::Layout;
+use crate::manifest::{Manifest, Selection};
+use crate::profile::Profiles;
+use crate::rename::RenameRules;
+use crate::source::{GroupFiles, PatchFile};
+use crate::state::{IceRecord, InstallState};
+use crate::swap::{Extracted, Swap};
+use crate::validate::{ValidationLevel, Validators};
+
 #[cfg(windows)]
 use nwg::NativeUi;
 
@@ -61,39 +102,320 @@ pub enum PatcherEvent {
 #[derive(Debug, StructOpt)]
 #[structopt(name = "pso2-modpatcher", about = "Tool for repacking ICE archives in a directory with new files")]
 struct Args {
+    #[structopt(long = "verbose", short = "v", global = true, help = "Print additional work information to stderr")]
+    verbose: bool,
+
+    #[structopt(long = "no-backup", global = true, help = "Don't create a backup of the patched files")]
+    no_backup: bool,
+
+    #[structopt(
+        long = "backup-dir",
+        global = true,
+        parse(from_os_str),
+        help = "Directory to keep backups in, instead of the backup directory in the data directory",
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "backup-link",
+        global = true,
+        possible_values = &["reflink", "hard"],
+        help = "Link backups to the original files instead of moving them. Hard links are only safe if nothing modifies ICE files in place",
+    )]
+    backup_link: Option<BackupLink>,
+
+    #[structopt(long = "keep", global = true, help = "Remove all but this many of the newest backup generations")]
+    keep: Option<usize>,
+
+    #[cfg(windows)]
+    #[structopt(long = "gui", help = "Show a gui window during patching instead of a console (Windows only)")]
+    gui: bool,
+
+    #[structopt(
+        long = "validate",
+        global = true,
+        default_value = "warn",
+        possible_values = &["off", "warn", "error"],
+        help = "How to report replacement files that look truncated or in the wrong format",
+    )]
+    validate: ValidationLevel,
+
+    #[structopt(
+        long = "cache-dir",
+        global = true,
+        parse(from_os_str),
+        help = "Directory in which to cache patched ICE files, reusing them when patching the same original with the same files",
+    )]
+    cache_dir: Option<PathBuf>,
+
+    #[structopt(long = "create-missing", global = true, help = "Create ICE files missing from the data directory instead of skipping them")]
+    create_missing: bool,
+
+    #[structopt(flatten)]
+    new_ice: IceFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// Arguments of the apply command, which patches a data directory.
+#[derive(Debug, StructOpt)]
+struct ApplyArgs {
     #[structopt(parse(from_os_str), help = "Patch path to apply")]
     input: PathBuf,
 
     #[structopt(parse(from_os_str), help = "Data directory to patch")]
     datadir: PathBuf,
 
-    #[structopt(long = "verbose", short = "v", help = "Print additional work information to stderr")]
-    verbose: bool,
+    #[structopt(long = "include", number_of_values = 1, help = "Only apply patch files whose <ice path>/<group>/<entry name> matches this glob (repeatable)")]
+    include: Vec<String>,
 
-    #[structopt(long = "no-backup", help = "Don't create a backup of the patched files")]
-    no_backup: bool,
+    #[structopt(long = "exclude", number_of_values = 1, help = "Don't apply patch files whose <ice path>/<group>/<entry name> matches this glob (repeatable)")]
+    exclude: Vec<String>,
 
-    #[cfg(windows)]
-    #[structopt(long = "gui", help = "Show a gui window during patching instead of a console (Windows only)")]
-    gui: bool,
+    #[structopt(long = "with", number_of_values = 1, help = "Install this optional component of a mod package (repeatable)")]
+    with: Vec<String>,
+
+    #[structopt(long = "without", number_of_values = 1, help = "Don't install this optional component of a mod package (repeatable)")]
+    without: Vec<String>,
+
+    #[structopt(
+        long = "variant",
+        number_of_values = 1,
+        parse(try_from_str = manifest::parse_variant),
+        help = "Install this variant of a mod package, as <group>=<choice> (repeatable)",
+    )]
+    variants: Vec<(String, String)>,
+
+    #[structopt(long = "dry-run", help = "Print which entries of which ICE files would be replaced or added, without patching")]
+    dry_run: bool,
 }
 
-fn iterate_patch_directory(src: &Path, out: &Path, backup_path: Option<&Path>, verbose: bool, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
-    if !src.is_dir() {
-        panic!("src is not a directory");
+// options for ICE files created from scratch, by pack or --create-missing.
+// Not a doc comment, as structopt would take it as the about text of the tool
+#[derive(Debug, Clone, StructOpt)]
+struct IceFormat {
+    #[structopt(long = "ice-version", global = true, default_value = "4", help = "ICE version of created files (3 or 4)")]
+    version: u32,
+
+    #[structopt(long = "encrypt", global = true, help = "Encrypt created ICE files")]
+    encrypt: bool,
+
+    #[structopt(long = "compress", global = true, help = "PRS-compress created ICE files")]
+    compress: bool,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Apply a patch directory to a data directory")]
+    Apply(ApplyArgs),
+
+    #[structopt(about = "Re-apply installed patches to ICE files replaced by a game update")]
+    Reapply {
+        #[structopt(parse(from_os_str), help = "Data directory to check")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "Check that installed patches are still intact")]
+    Verify {
+        #[structopt(parse(from_os_str), help = "Data directory to check")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "List the ICE files patched in a data directory and whether they are still as patched")]
+    Status {
+        #[structopt(parse(from_os_str), help = "Data directory to report on")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "Manage named sets of patches to switch the data directory between")]
+    Profile(ProfileCommand),
+
+    #[structopt(about = "Remove backups no longer referenced by any backup generation or patched ICE file")]
+    Gc {
+        #[structopt(parse(from_os_str), help = "Data directory whose backups to clean up")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "Record the entries of every ICE file in a data directory, for find")]
+    Index {
+        #[structopt(parse(from_os_str), help = "Data directory to index")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "List the ICE files holding entries whose names match a glob, using the index")]
+    Find {
+        #[structopt(parse(from_os_str), help = "Data directory to search")]
+        datadir: PathBuf,
+
+        #[structopt(help = "Glob to match entry names against, e.g. \"pl_*_123.aqp\"")]
+        pattern: String,
+    },
+
+    #[structopt(about = "Compare the headers and entries of two ICE files")]
+    Diff {
+        #[structopt(parse(from_os_str), help = "ICE file to compare from")]
+        a: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "ICE file to compare to")]
+        b: PathBuf,
+
+        #[structopt(long = "json", help = "Print the differences as JSON")]
+        json: bool,
+    },
+
+    #[structopt(about = "Generate a patch directory from the ICE files changed in a data directory")]
+    MakePatch {
+        #[structopt(parse(from_os_str), help = "Original data directory, or a backup directory holding the originals")]
+        original: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "Modified data directory")]
+        modified: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "Patch directory to write")]
+        out: PathBuf,
+
+        #[structopt(long = "generation", help = "Backup generation to take originals from, instead of the newest backup of each file")]
+        generation: Option<String>,
+    },
+
+    #[structopt(about = "Create a new ICE file from a directory containing 1 and 2 group directories")]
+    Pack {
+        #[structopt(parse(from_os_str), help = "Directory to pack")]
+        dir: PathBuf,
+
+        #[structopt(parse(from_os_str), help = "ICE file to write")]
+        out: PathBuf,
+    },
+
+    #[structopt(about = "List files in a patch directory that would be ignored or can't be patched")]
+    Lint {
+        #[structopt(parse(from_os_str), help = "Patch path to check")]
+        input: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ProfileCommand {
+    #[structopt(about = "List the profiles of a data directory")]
+    List {
+        #[structopt(parse(from_os_str), help = "Data directory the profiles belong to")]
+        datadir: PathBuf,
+    },
+
+    #[structopt(about = "Create or replace a profile")]
+    Set {
+        #[structopt(parse(from_os_str), help = "Data directory the profile belongs to")]
+        datadir: PathBuf,
+
+        #[structopt(help = "Name of the profile")]
+        name: String,
+
+        #[structopt(parse(from_os_str), help = "Patch paths to apply, in order. None restores the original files")]
+        patches: Vec<PathBuf>,
+    },
+
+    #[structopt(about = "Patch the data directory with exactly the patches of a profile")]
+    Switch {
+        #[structopt(parse(from_os_str), help = "Data directory to switch")]
+        datadir: PathBuf,
+
+        #[structopt(help = "Name of the profile")]
+        name: String,
+    },
+}
+
+/// Settings and state shared by every ICE file patched in a run.
+struct PatchContext {
+    datadir: PathBuf,
+    verbose: bool,
+    events: mpsc::Sender<PatcherEvent>,
+    state: InstallState,
+    /// Format to create missing ICE files in, if they should be created.
+    new_ice: Option<IceFormat>,
+    validators: Validators,
+    /// Cache of earlier outputs to copy instead of rebuilding, if enabled.
+    cache: Option<PatchCache>,
+    /// Where original ICE files are backed up to, if they are.
+    backups: Option<BackupStore>,
+    /// The backup directory, skipped when indexing the data directory.
+    backup_dir: PathBuf,
+    /// Which files of patches to apply, if not all of them.
+    filter: Option<PatchFilter>,
+    /// Data directory and install state to copy the entries of swaps from
+    /// while `datadir` and `state` are those of a staging directory.
+    staged_from: Option<(PathBuf, InstallState)>,
+}
+
+impl PatchContext {
+    /// Scan the files of an `_ice` patch directory, with [`source::scan_patch`],
+    /// copying the entries of swaps from the real data directory.
+    fn scan_patch(&self, patch_src: &Path) -> anyhow::Result<([GroupFiles; 2], Option<Extracted>)> {
+        match &self.staged_from {
+            Some((datadir, state)) => source::scan_patch(patch_src, datadir, state),
+            None => source::scan_patch(patch_src, &self.datadir, &self.state),
+        }
     }
-    if !out.is_dir() {
-        panic!("out is not a directory");
+}
+
+/// An `_ice` patch directory and the ICE file it applies to.
+struct PatchTarget {
+    source: PathBuf,
+    target: PathBuf,
+}
+
+fn iterate_patch_directory(plan: Vec<PatchTarget>, ctx: &mut PatchContext) -> anyhow::Result<()> {
+    for PatchTarget { source, target } in plan {
+        match apply_directory(&source, &target, ctx)
+            .with_context(|| format!("Failed to patch ICE file {}", target.to_string_lossy())) {
+            Err(e) => {
+                eprintln!("{:?}\nContinuing...", e);
+            },
+            _ => {},
+        }
     }
-    if let Some(backup_path) = backup_path {
-        if backup_path.exists() && !backup_path.is_dir() {
-            panic!("backup path is not a directory");
+
+    Ok(())
+}
+
+/// Find the `_ice` directories to apply from a patch directory: all of them
+/// without a manifest, or otherwise those outside the optional subdirectories
+/// followed by those in the selected ones, and then those replacing entries
+/// everywhere in the data directory `out` with install state `state`. The
+/// latter are expanded from the manifest into the returned directory, which
+/// must be kept until the plan is carried out.
+fn plan_patch(
+    src: &Path,
+    out: &Path,
+    package: Option<(&Manifest, &Selection)>,
+    backup_dir: &Path,
+    state: &InstallState,
+    dry_run: bool,
+    verbose: bool,
+) -> anyhow::Result<(Vec<PatchTarget>, Option<Expanded>)> {
+    let mut plan = Vec::new();
+    let mut expanded = None;
+    plan_patch_directory(src, out, None, verbose, &mut plan)?;
+    if let Some((manifest, selection)) = package {
+        let optional: Vec<PathBuf> = manifest.paths().map(|p| src.join(p)).collect();
+        plan.retain(|t| !optional.iter().any(|o| t.source.starts_with(o)));
+        for path in manifest.selected_paths(selection) {
+            plan_patch_directory(&src.join(path), out, None, verbose, &mut plan)?;
         }
-        if !backup_path.exists() {
-            std::fs::create_dir_all(backup_path)
-                .with_context(|| "Failed to make backup directory")?;
+        expanded = everywhere::expand(src, &manifest.everywhere, out, backup_dir, state, dry_run, verbose)?;
+        if let Some(expanded) = &expanded {
+            plan_patch_directory(expanded.dir(), out, None, verbose, &mut plan)?;
         }
     }
+    Ok((plan, expanded))
+}
+
+/// Find the `_ice` directories within a patch directory, and the ICE files
+/// under `out` they apply to.
+fn plan_patch_directory(src: &Path, out: &Path, layout: Option<Layout>, verbose: bool, plan: &mut Vec<PatchTarget>) -> anyhow::Result<()> {
+    if !src.is_dir() {
+        panic!("src is not a directory");
+    }
 
     if verbose {
         eprintln!("Working on patch source directory {}", src.to_string_lossy());
@@ -110,24 +432,20 @@ fn iterate_patch_directory(src: &Path, out: &Path, backup_path: Option<&Path>, v
             if file_name_lossy == "backup" {
                 bail!("File name of a patch directory in {} is \"backup\", which is not allowed", src.to_string_lossy());
             }
-            if file_name_lossy.ends_with("_ice") {
+            if let Some(ice_name) = file_name_lossy.strip_suffix("_ice") {
                 // this is an ice file to patch
-                let ice_out = out.join(file_name_lossy.strip_suffix("_ice").unwrap());
-                let backup_file = backup_path.map(|p| p.join(file_name_lossy.strip_suffix("_ice").unwrap()));
-
-                match apply_directory(&file_entry_path, &ice_out, backup_file.as_ref().map(|p| p.as_path()), verbose, events.clone())
-                    .with_context(|| format!("Failed to patch ICE file {}", ice_out.to_string_lossy())) {
-                    Err(e) => {
-                        eprintln!("{:?}\nContinuing...", e);
-                    },
-                    _ => {},
-                }
+                let parent_name = out.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+                let ice_path = layout::ice_path(layout, &parent_name, ice_name);
+                plan.push(PatchTarget {
+                    source: file_entry_path.clone(),
+                    target: out.join(&ice_path),
+                });
             } else {
                 // this is another directory to iterate
                 let out_path = out.join(file_name);
-                let next_backup_path = backup_path.map(|p| p.join(file_name));
+                let next_layout = layout.or_else(|| Layout::from_dir_name(&file_name_lossy));
 
-                match iterate_patch_directory(&file_entry_path, &out_path, next_backup_path.as_ref().map(|p| p.as_path()), verbose, events.clone())
+                match plan_patch_directory(&file_entry_path, &out_path, next_layout, verbose, plan)
                     .with_context(|| format!("Failed to apply directory {}", out_path.to_string_lossy())) {
                     Err(e) => {
                         eprintln!("{:?}\nContinuing...", e);
@@ -141,7 +459,7 @@ fn iterate_patch_directory(src: &Path, out: &Path, backup_path: Option<&Path>, v
     Ok(())
 }
 
-fn apply_directory(patch_src: &Path, out_file: &Path, backup_file: Option<&Path>, verbose: bool, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
+fn apply_directory(patch_src: &Path, out_file: &Path, ctx: &mut PatchContext) -> anyhow::Result<()> {
     // The patch_src is assumed to contain two directories, 1 and 2
     // Each correspond to a group in the out_file ICE to replace files in
 
@@ -150,33 +468,73 @@ fn apply_directory(patch_src: &Path, out_file: &Path, backup_file: Option<&Path>
         panic!("patch src was not a directory");
     }
 
-    if !out_file.exists() {
+    if !out_file.exists() && ctx.new_ice.is_none() {
         // not a failure, but we can't apply this patch
         eprintln!("{} missing; skipping", out_file.to_string_lossy());
         return Ok(());
     }
 
+    let state_key = state::key(&ctx.datadir, out_file);
+    // entries copied by a swap are removed once this is dropped
+    let (mut files, _extracted) = ctx.scan_patch(patch_src)?;
+    filter_files(&mut files, &state_key, ctx.filter.as_ref());
+    if ctx.filter.is_some() && files.iter().all(|f| f.is_empty()) {
+        if ctx.verbose {
+            eprintln!("Every file of {} is excluded; skipping", patch_src.to_string_lossy());
+        }
+        return Ok(());
+    }
+    // every file adds an entry to an ICE that is created
+    check_entry_names(&[&files[0], &files[1]], !out_file.exists())?;
+    // before anything is backed up, so that a refused file leaves the ICE as
+    // it was
+    validate_files(&files, &ctx.validators)?;
+
+    if !out_file.exists() {
+        return create_ice(patch_src, out_file, files, ctx);
+    }
+
     if !out_file.is_file() {
         panic!("out file is not a file");
     }
 
-    let mut src_1 = patch_src.to_path_buf();
-    src_1.push("1");
-    let mut src_2 = patch_src.to_path_buf();
-    src_2.push("2");
+    let [g1_files, g2_files] = files;
+    // the rules have already been applied to the files; these are for the
+    // entries of the original
+    let rename = RenameRules::load(patch_src)?;
 
-    if src_1.exists() && !src_1.is_dir() {
-        bail!("1 in patch directory {} is not a directory", patch_src.to_string_lossy());
-    }
-    if src_2.exists() && !src_2.is_dir() {
-        bail!("2 in patch directory {} is not a directory", patch_src.to_string_lossy());
-    }
-    if !src_1.exists() && !src_2.exists() {
-        bail!("Patch directory {} does not contain any files to patch", patch_src.to_string_lossy());
+    if ctx.verbose {
+        eprintln!("Patching ICE file {}", out_file.to_string_lossy());
     }
 
-    if verbose {
-        eprintln!("Patching ICE file {}", out_file.to_string_lossy());
+    let original_hash = hash::hash_file(out_file)
+        .with_context(|| format!("Failed to hash target ICE file \"{}\"", out_file.to_string_lossy()))?;
+    let record = ctx.state.ices.get(&state_key);
+    // if we wrote the current file, this patch is stacked on top of our own
+    // output; otherwise the game has replaced it and it is a new original
+    let stacked = record.is_some_and(|r| r.patched_hash == original_hash);
+    let replaced_since_patch = record.is_some() && !stacked;
+
+    let cached = match &ctx.cache {
+        Some(cache) => Some(cache.key(&original_hash, &[&g1_files, &g2_files], rename.as_ref())?),
+        None => None,
+    };
+    if let (Some(cache), Some((cache_key, _))) = (&ctx.cache, &cached) {
+        let fetched_file = partial_path(out_file);
+        if let Some(patched_hash) = cache.fetch(cache_key, &fetched_file)? {
+            if ctx.verbose {
+                eprintln!("Using cached output for {}", out_file.to_string_lossy());
+            }
+            let backup_file = back_up(out_file, &state_key, &original_hash, stacked, replaced_since_patch, ctx)?;
+            std::fs::rename(&fetched_file, out_file)
+                .with_context(|| format!(
+                    "Failed to move cached output {} into place at {}",
+                    fetched_file.to_string_lossy(),
+                    out_file.to_string_lossy(),
+                ))?;
+            record_patch(ctx, patch_src, out_file, backup_file, stacked, original_hash, patched_hash);
+            return Ok(());
+        }
     }
 
     let orig_ia_file = File::open(out_file)
@@ -187,25 +545,22 @@ fn apply_directory(patch_src: &Path, out_file: &Path, backup_file: Option<&Path>
             out_file.to_string_lossy(),
         ))?;
 
-    if let Some(backup_file) = backup_file {
-        if !backup_file.exists() {
-            if let Some(_backup_parent) = backup_file.parent() {
-                if verbose {
-                    eprintln!("Backing up {} to {}", out_file.to_string_lossy(), backup_file.to_string_lossy());
-                }
-                std::fs::rename(out_file, backup_file)
-                    .with_context(|| format!(
-                        "Failed to copy the target ICE file {} to the backup path {}",
-                        out_file.to_string_lossy(),
-                        backup_file.to_string_lossy(),
-                    ))?;
-            } else {
-                panic!("backup path parent does not exist");
-            }
-        } else {
-            eprintln!("Backup file {} exists; not replacing it with a new backup", backup_file.to_string_lossy());
+    // only now that the original's entries are known; a cached output was
+    // built from the same original and files, so they passed this then
+    check_new_entries(&orig_ia, &[&g1_files, &g2_files], rename.as_ref(), out_file)?;
+
+    // a file the game replaced since we patched it is rebuilt regardless, so
+    // that its backup and record are brought up to date
+    if !replaced_since_patch && already_applied(&orig_ia, &[&g1_files, &g2_files], rename.as_ref(), out_file)? {
+        eprintln!("{} already contains {}; skipping", out_file.to_string_lossy(), patch_src.to_string_lossy());
+        if let Some(record) = ctx.state.ices.get_mut(&state_key) {
+            add_source(record, patch_src, ctx.filter.as_ref());
         }
+        let _e = ctx.events.send(PatcherEvent::Progress);
+        return Ok(());
     }
+
+    let backup_file = back_up(out_file, &state_key, &original_hash, stacked, replaced_since_patch, ctx)?;
     
     if orig_ia.version() != 4 {
         bail!(
@@ -224,260 +579,945 @@ fn apply_directory(patch_src: &Path, out_file: &Path, backup_file: Option<&Path>
     let mut new_ia = IceWriter::new(4, compress, encrypt, oodle)
         .with_context(|| "Unable to start creating new ICE archive")?;
     
+    // Groups are unpacked one at a time, each released once its entries are
+    // in the writer, and the original archive is released once group 2 is
+    // unpacked. Replacement files are read one at a time as their entries are
+    // written. The writer keeps its own copy of every entry and composes the
+    // whole output in memory, as group sizes and checksums lead the file and
+    // groups are compressed and encrypted whole, so this bounds peak memory
+    // at roughly twice the output rather than streaming it.
     let orig_g1_data = orig_ia.decompress_group(Group::Group1)
         .with_context(|| format!(
             "Failed to unpack group 1 of {}",
             out_file.to_string_lossy(),
         ))?;
+    let mut g1_added_files = patch_group(
+        &mut new_ia,
+        group_iter(&orig_g1_data, orig_ia.group_count(Group::Group1), Group::Group1, out_file)?,
+        &g1_files,
+        rename.as_ref(),
+        Group::Group1,
+        out_file,
+    )?;
+    drop(orig_g1_data);
+    add_new_files(&mut new_ia, &g1_files, Group::Group1, &mut g1_added_files)?;
+
     let orig_g2_data = orig_ia.decompress_group(Group::Group2)
         .with_context(|| format!(
             "Failed to unpack group 2 of {}",
             out_file.to_string_lossy(),
         ))?;
-    
-    let orig_g1_files_iter: IceGroupIter = match IceGroupIter::new(&orig_g1_data[..], orig_ia.group_count(Group::Group1)) {
-        Ok(i) => i,
-        Err(_) => bail!(
-            "Unable to iterate over group 1 files in {}",
-            out_file.to_string_lossy(),
-        ),
+    let g2_count = orig_ia.group_count(Group::Group2);
+    drop(orig_ia);
+    let mut g2_added_files = patch_group(
+        &mut new_ia,
+        group_iter(&orig_g2_data, g2_count, Group::Group2, out_file)?,
+        &g2_files,
+        rename.as_ref(),
+        Group::Group2,
+        out_file,
+    )?;
+    drop(orig_g2_data);
+    add_new_files(&mut new_ia, &g2_files, Group::Group2, &mut g2_added_files)?;
+
+    let patched_hash = write_ice(&new_ia, out_file, patch_src)?;
+
+    if let (Some(cache), Some((cache_key, inputs))) = (&ctx.cache, cached) {
+        let entry = CacheEntry {
+            target: state_key,
+            original_hash: original_hash.clone(),
+            inputs,
+            output_hash: patched_hash.clone(),
+        };
+        if let Err(e) = cache.store(&cache_key, out_file, &entry) {
+            eprintln!("Warning: failed to cache {}: {:#}", out_file.to_string_lossy(), e);
+        }
+    }
+
+    record_patch(ctx, patch_src, out_file, backup_file, stacked, original_hash, patched_hash);
+
+    Ok(())
+}
+
+/// Back up the target ICE file if it is an original.
+/// Returns the path of the new backup, if one was made.
+fn back_up(out_file: &Path, state_key: &str, original_hash: &str, stacked: bool, replaced_since_patch: bool, ctx: &mut PatchContext) -> anyhow::Result<Option<PathBuf>> {
+    // our own output is never backed up; the original it was made from
+    // already was, if backups were enabled at the time
+    if stacked {
+        return Ok(None);
+    }
+    let backups = match &mut ctx.backups {
+        Some(b) => b,
+        None => return Ok(None),
     };
+    if replaced_since_patch {
+        eprintln!("{} changed since it was last patched; backing up the new original", out_file.to_string_lossy());
+    }
+    backups.back_up(out_file, state_key, original_hash, ctx.verbose).map(Some)
+}
 
-    let mut g1_added_files: HashSet<String> = HashSet::new();
-    for file in orig_g1_files_iter {
-        // unwrap here as these don't have std errors yet and it is exceedingly
-        // unlikely to find a malformed ICE archive at this point
-        let ext = file.ext().unwrap();
-        let name = file.name().unwrap();
-        let data = file.data();
+/// Record a patched ICE file in the install state and report progress.
+fn record_patch(
+    ctx: &mut PatchContext,
+    patch_src: &Path,
+    out_file: &Path,
+    backup_file: Option<PathBuf>,
+    stacked: bool,
+    original_hash: String,
+    patched_hash: String,
+) {
+    let state_key = state::key(&ctx.datadir, out_file);
+    match ctx.state.ices.get_mut(&state_key) {
+        Some(record) if stacked => {
+            add_source(record, patch_src, ctx.filter.as_ref());
+            record.patched_hash = patched_hash;
+            record.patched_at = state::now();
+        },
+        _ => {
+            let mut record = IceRecord {
+                sources: Vec::new(),
+                filters: BTreeMap::new(),
+                original_hash: Some(original_hash),
+                patched_hash,
+                backup: backup_file,
+                patched_at: state::now(),
+            };
+            add_source(&mut record, patch_src, ctx.filter.as_ref());
+            ctx.state.ices.insert(state_key, record);
+        },
+    }
 
-        let name_ascii = unsafe { AsciiStr::from_ascii_unchecked(name.as_bytes()) };
-        let ext_ascii = unsafe { AsciiStr::from_ascii_unchecked(ext.as_bytes()) };
+    // event sender is allowed to fail (for no receivers)
+    let _e = ctx.events.send(PatcherEvent::Progress);
+}
 
-        let replacer_path = src_1.join(name);
-        if replacer_path.exists() {
-            if !replacer_path.is_file() {
-                bail!(
-                    "Replacement path {} for group 1 of {} is not a file",
-                    replacer_path.to_string_lossy(),
-                    out_file.to_string_lossy(),
-                );
-            }
+/// Add a patch source to a record, along with the filter it was applied with.
+fn add_source(record: &mut IceRecord, patch_src: &Path, filter: Option<&PatchFilter>) {
+    let source = std::fs::canonicalize(patch_src).unwrap_or_else(|_| patch_src.to_path_buf());
+    match filter {
+        Some(filter) => {
+            record.filters.insert(source.clone(), filter.clone());
+        },
+        None => {
+            record.filters.remove(&source);
+        },
+    }
+    if !record.sources.contains(&source) {
+        record.sources.push(source);
+    }
+}
 
-            let replacer_file = std::fs::read(&replacer_path)
-                .with_context(|| format!(
-                    "Failed to open replacement file {} for group 1 of {}",
-                    replacer_path.to_string_lossy(),
-                    out_file.to_string_lossy(),
-                ))?;
-            
-            let mut of = new_ia.begin_file(name_ascii, ext_ascii, Group::Group1);
-            of
-                .write_all(&replacer_file[..])
-                .with_context(|| format!(
-                    "Failed to write replacement {} in group 1 of {}",
-                    replacer_path.to_string_lossy(),
-                    out_file.to_string_lossy(),
-                ))?;
-            of.finish();
-            g1_added_files.insert(name.to_owned());
-        } else {
-            let mut of = new_ia.begin_file(name_ascii, ext_ascii, Group::Group1);
-            of
-                .write_all(&data[..])
-                .with_context(|| format!(
-                    "Failed to write {} in group 1 of {}",
-                    name,
-                    out_file.to_string_lossy(),
-                ))?;
-            of.finish();
-            g1_added_files.insert(name.to_owned());
+/// Drop the files of a patch for the ICE file `key` that the filter excludes.
+fn filter_files(files: &mut [GroupFiles; 2], key: &str, filter: Option<&PatchFilter>) {
+    if let Some(filter) = filter {
+        for (i, group_files) in files.iter_mut().enumerate() {
+            group_files.retain(|f| filter.includes(key, i + 1, &f.name));
         }
     }
+}
+
+/// Check whether every file of a patch already matches the entries it would
+/// replace and no entry would be renamed, in which case patching would not
+/// change the archive.
+fn already_applied(orig_ia: &IceArchive, files: &[&GroupFiles; 2], rename: Option<&RenameRules>, out_file: &Path) -> anyhow::Result<bool> {
+    for &group in ice::GROUPS.iter() {
+        let files = files[ice::group_index(group)];
+        if files.iter().next().is_none() && rename.is_none() {
+            continue;
+        }
+
+        let mut file_hashes: HashMap<&str, String> = HashMap::new();
+        for (key, file) in files.iter() {
+            let file_hash = hash::hash_file(&file.path)
+                .with_context(|| format!("Failed to hash replacement file {}", file.path.to_string_lossy()))?;
+            file_hashes.insert(key, file_hash);
+        }
 
-    if src_1.exists() {
-        for file in src_1.read_dir().with_context(|| format!("Unable to read dir {} for adding files to {}", src_1.to_string_lossy(), out_file.to_string_lossy()))? {
-            let file = file.with_context(|| format!(
-                "Unable to index file while reading dir {} for adding files to {}",
-                src_1.to_string_lossy(),
+        let orig_data = orig_ia.decompress_group(group)
+            .with_context(|| format!(
+                "Failed to unpack group {} of {}",
+                ice::group_index(group) + 1,
                 out_file.to_string_lossy(),
             ))?;
+        let orig_files_iter = group_iter(&orig_data, orig_ia.group_count(group), group, out_file)?;
 
-            let file_name_string = file.file_name().to_string_lossy().into_owned();
-            if !g1_added_files.contains(&file_name_string) {
-                let ascii_name = AsciiString::from_ascii(file_name_string.as_bytes().to_owned())
-                    .with_context(|| format!(
-                        "File name of {} is not valid ASCII",
-                        file.path().to_string_lossy(),
-                    ))?;
-                let ascii_ext = match file.path().extension() {
-                    Some(e) => {
-                        let e_owned = e.to_string_lossy().into_owned();
-                        AsciiString::from_ascii(e_owned.as_bytes().to_owned()).with_context(|| format!(
-                            "File extension of {} is not valid ASCII",
-                            file.path().to_string_lossy(),
-                        ))?.to_owned()
-                    },
-                    None => bail!("File {} has no extension", file.path().to_string_lossy()),
-                };
-                let fc = std::fs::read(file.path())
-                    .with_context(|| format!(
-                        "Unable to read contents of file {}",
-                        file.path().to_string_lossy(),
-                    ))?;
-                let mut of = new_ia.begin_file(&ascii_name, &ascii_ext, Group::Group1);
-                of.write_all(&fc[..])
-                    .with_context(|| format!(
-                        "Unable to write contents of file {} to ICE file writer",
-                        file.path().to_string_lossy(),
-                    ))?;
-                of.finish();
-                g1_added_files.insert(file_name_string);
+        let mut matched = HashSet::new();
+        for file in orig_files_iter {
+            let name = file.name().unwrap();
+            if rename.is_some_and(|r| r.apply(name) != name) {
+                return Ok(false);
+            }
+            let name_key = name.to_lowercase();
+            if let Some(file_hash) = file_hashes.get(name_key.as_str()) {
+                if hash::hash_bytes(file.data()) != *file_hash {
+                    return Ok(false);
+                }
+                matched.insert(name_key);
             }
         }
+        // anything left over would be added as a new entry
+        if matched.len() != file_hashes.len() {
+            return Ok(false);
+        }
     }
+    Ok(true)
+}
 
-    let orig_g2_files_iter: IceGroupIter = match IceGroupIter::new(&orig_g2_data[..], orig_ia.group_count(Group::Group2)) {
-        Ok(i) => i,
+/// Iterate over the entries of group `group` of `out_file`, unpacked as `data`.
+fn group_iter<'a>(data: &'a [u8], count: u32, group: Group, out_file: &Path) -> anyhow::Result<IceGroupIter<'a>> {
+    match IceGroupIter::new(data, count) {
+        Ok(i) => Ok(i),
         Err(_) => bail!(
-            "Unable to iterate over group 2 files in {}",
+            "Unable to iterate over group {} files in {}",
+            ice::group_index(group) + 1,
             out_file.to_string_lossy(),
         ),
-    };
+    }
+}
+
+/// Write every entry of an original ICE group to `new_ia`, renamed by
+/// `rename`, substituting files of the same name from `files`. Returns the
+/// lowercased names of the entries written.
+fn patch_group(
+    new_ia: &mut IceWriter,
+    orig_files_iter: IceGroupIter,
+    files: &GroupFiles,
+    rename: Option<&RenameRules>,
+    group: Group,
+    out_file: &Path,
+) -> anyhow::Result<HashSet<String>> {
+    let group_num = ice::group_index(group) + 1;
 
-    let mut g2_added_files: HashSet<String> = HashSet::new();
-    for file in orig_g2_files_iter {
+    let mut added_files: HashSet<String> = HashSet::new();
+    for file in orig_files_iter {
         // unwrap here as these don't have std errors yet and it is exceedingly
         // unlikely to find a malformed ICE archive at this point
-        let ext = file.ext().unwrap();
-        let name = file.name().unwrap();
+        let orig_ext = file.ext().unwrap();
+        let orig_name = file.name().unwrap();
         let data = file.data();
 
+        let (name, ext) = match rename.map(|r| r.apply(orig_name)) {
+            Some(renamed) if renamed != orig_name => {
+                // checked to be a valid new entry name by check_new_entries
+                let ext = renamed.rsplit_once('.').unwrap().1.to_owned();
+                (renamed.into_owned(), ext)
+            },
+            _ => (orig_name.to_owned(), orig_ext.to_owned()),
+        };
+        let name = name.as_str();
         let name_ascii = unsafe { AsciiStr::from_ascii_unchecked(name.as_bytes()) };
         let ext_ascii = unsafe { AsciiStr::from_ascii_unchecked(ext.as_bytes()) };
 
-        let replacer_path = src_2.join(name);
-        if replacer_path.exists() {
+        let name_key = name.to_lowercase();
+        let duplicate = !added_files.insert(name_key);
+
+        if let Some(replacer) = files.get(name) {
+            let replacer_path = replacer.path.as_path();
             if !replacer_path.is_file() {
                 bail!(
-                    "Replacement path {} for group 2 of {} is not a file",
+                    "Replacement path {} for group {} of {} is not a file",
+                    replacer_path.to_string_lossy(),
+                    group_num,
+                    out_file.to_string_lossy(),
+                );
+            }
+            if duplicate {
+                eprintln!(
+                    "Warning: group {} of {} has several entries named {} differing only by case; {} replaces all of them",
+                    group_num,
+                    out_file.to_string_lossy(),
+                    name,
+                    replacer_path.to_string_lossy(),
+                );
+            }
+            if replacer.name != name {
+                eprintln!(
+                    "Warning: {} replaces entry {} in group {} of {}, but the case of the names differs",
                     replacer_path.to_string_lossy(),
+                    name,
+                    group_num,
                     out_file.to_string_lossy(),
                 );
             }
 
-            let replacer_file = std::fs::read(&replacer_path)
+            let replacer_file = std::fs::read(replacer_path)
                 .with_context(|| format!(
-                    "Failed to open replacement file {} for group 2 of {}",
+                    "Failed to open replacement file {} for group {} of {}",
                     replacer_path.to_string_lossy(),
+                    group_num,
                     out_file.to_string_lossy(),
                 ))?;
-            
-            let mut of = new_ia.begin_file(name_ascii, ext_ascii, Group::Group2);
+
+            let mut of = new_ia.begin_file(name_ascii, ext_ascii, group);
             of
                 .write_all(&replacer_file[..])
                 .with_context(|| format!(
-                    "Failed to write replacement {} in group 2 of {}",
+                    "Failed to write replacement {} in group {} of {}",
                     replacer_path.to_string_lossy(),
+                    group_num,
                     out_file.to_string_lossy(),
                 ))?;
             of.finish();
-            g2_added_files.insert(name.to_owned());
         } else {
-            let mut of = new_ia.begin_file(name_ascii, ext_ascii, Group::Group2);
+            let mut of = new_ia.begin_file(name_ascii, ext_ascii, group);
             of
                 .write_all(&data[..])
                 .with_context(|| format!(
-                    "Failed to write {} in group 2 of {}",
+                    "Failed to write {} in group {} of {}",
                     name,
+                    group_num,
                     out_file.to_string_lossy(),
                 ))?;
             of.finish();
-            g2_added_files.insert(name.to_owned());
         }
     }
 
-    if src_2.exists() {
-        for file in src_2.read_dir().with_context(|| format!("Unable to read dir {} for adding files to {}", src_2.to_string_lossy(), out_file.to_string_lossy()))? {
-            let file = file.with_context(|| format!(
-                "Unable to index file while reading dir {} for adding files to {}",
-                src_2.to_string_lossy(),
+    Ok(added_files)
+}
+
+/// Append every file in `files` not already in `added_files` to `group` of
+/// `new_ia`.
+fn add_new_files(new_ia: &mut IceWriter, files: &GroupFiles, group: Group, added_files: &mut HashSet<String>) -> anyhow::Result<()> {
+    for (key, file) in files.iter() {
+        if !added_files.contains(key) {
+            let path = file.path.as_path();
+            let ascii_name = AsciiString::from_ascii(file.name.as_bytes().to_owned())
+                .with_context(|| format!(
+                    "Entry name {} of {} is not valid ASCII",
+                    file.name,
+                    path.to_string_lossy(),
+                ))?;
+            let ascii_ext = match Path::new(&file.name).extension() {
+                Some(e) => {
+                    let e_owned = e.to_string_lossy().into_owned();
+                    AsciiString::from_ascii(e_owned.as_bytes().to_owned()).with_context(|| format!(
+                        "File extension of {} is not valid ASCII",
+                        path.to_string_lossy(),
+                    ))?.to_owned()
+                },
+                None => bail!("File {} has no extension", path.to_string_lossy()),
+            };
+            let fc = std::fs::read(path)
+                .with_context(|| format!(
+                    "Unable to read contents of file {}",
+                    path.to_string_lossy(),
+                ))?;
+            let mut of = new_ia.begin_file(&ascii_name, &ascii_ext, group);
+            of.write_all(&fc[..])
+                .with_context(|| format!(
+                    "Unable to write contents of file {} to ICE file writer",
+                    path.to_string_lossy(),
+                ))?;
+            of.finish();
+            added_files.insert(key.to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every file in the groups can be stored as an entry, and if
+/// `all_new`, that each can be a new entry, failing with a list of all the
+/// files that can't.
+fn check_entry_names(groups: &[&GroupFiles], all_new: bool) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for files in groups.iter() {
+        for (_, file) in files.iter() {
+            let result = match file.path.file_name().and_then(|n| n.to_str()) {
+                Some(_) if all_new => ice::check_new_entry_name(&file.name),
+                Some(_) => ice::check_entry_name(&file.name),
+                None => Err("name is not valid Unicode".to_owned()),
+            };
+            if let Err(e) = result {
+                problems.push(describe_name_problem(file, &e));
+            }
+        }
+    }
+    fail_on_name_problems(problems)
+}
+
+/// Check that the entries of the original archive `orig_ia` renamed by
+/// `rename`, and the files of a patch adding entries to it rather than
+/// replacing them, can be new entries, failing with a list of all that can't.
+fn check_new_entries(orig_ia: &IceArchive, files: &[&GroupFiles; 2], rename: Option<&RenameRules>, out_file: &Path) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for &group in ice::GROUPS.iter() {
+        // only files that couldn't be new entries need the original's
+        let refused: Vec<(&str, &PatchFile, String)> = files[ice::group_index(group)]
+            .iter()
+            .filter_map(|(key, file)| ice::check_new_entry_name(&file.name).err().map(|e| (key, file, e)))
+            .collect();
+        if refused.is_empty() && rename.is_none() {
+            continue;
+        }
+
+        let orig_data = orig_ia.decompress_group(group)
+            .with_context(|| format!(
+                "Failed to unpack group {} of {}",
+                ice::group_index(group) + 1,
                 out_file.to_string_lossy(),
             ))?;
-
-            let file_name_string = file.file_name().to_string_lossy().into_owned();
-            if !g2_added_files.contains(&file_name_string) {
-                let ascii_name = AsciiString::from_ascii(file_name_string.as_bytes().to_owned())
-                    .with_context(|| format!(
-                        "File name of {} is not valid ASCII",
-                        file.path().to_string_lossy(),
-                    ))?;
-                let ascii_ext = match file.path().extension() {
-                    Some(e) => {
-                        let e_owned = e.to_string_lossy().into_owned();
-                        AsciiString::from_ascii(e_owned.as_bytes().to_owned()).with_context(|| format!(
-                            "File extension of {} is not valid ASCII",
-                            file.path().to_string_lossy(),
-                        ))?.to_owned()
-                    },
-                    None => bail!("File {} has no extension", file.path().to_string_lossy()),
-                };
-                let fc = std::fs::read(file.path())
-                    .with_context(|| format!(
-                        "Unable to read contents of file {}",
-                        file.path().to_string_lossy(),
-                    ))?;
-                let mut of = new_ia.begin_file(&ascii_name, &ascii_ext, Group::Group2);
-                of.write_all(&fc[..])
-                    .with_context(|| format!(
-                        "Unable to write contents of file {} to ICE file writer",
-                        file.path().to_string_lossy(),
-                    ))?;
-                of.finish();
-                g2_added_files.insert(file_name_string);
+        let mut existing = HashSet::new();
+        for file in group_iter(&orig_data, orig_ia.group_count(group), group, out_file)? {
+            let name = file.name().unwrap();
+            let renamed = rename.map(|r| r.apply(name)).unwrap_or(name.into());
+            if renamed != name {
+                if let Err(e) = ice::check_new_entry_name(&renamed) {
+                    problems.push(format!(
+                        "entry {} in group {} of {} (renamed to {}): {}",
+                        name,
+                        ice::group_index(group) + 1,
+                        out_file.to_string_lossy(),
+                        renamed,
+                        e,
+                    ));
+                }
+            }
+            existing.insert(renamed.to_lowercase());
+        }
+        for (key, file, e) in refused {
+            if !existing.contains(key) {
+                problems.push(describe_name_problem(file, &e));
             }
         }
     }
+    fail_on_name_problems(problems)
+}
 
-    let new_ia_file = File::create(out_file)
-        .with_context(|| format!(
-            "Unable to open ICE file path {} for writing patched archive from {}",
-            out_file.to_string_lossy(),
-            patch_src.to_string_lossy(),
-        ))?;
-    
-    new_ia.finish(new_ia_file)
-        .with_context(|| format!(
-            "Unable to write patched ICE archive to {}",
-            out_file.to_string_lossy(),
-        ))?;
+fn describe_name_problem(file: &PatchFile, problem: &str) -> String {
+    if file.path.file_name().is_some_and(|n| n.to_string_lossy() != file.name) {
+        format!("{} (renamed to {}): {}", file.path.to_string_lossy(), file.name, problem)
+    } else {
+        format!("{}: {}", file.path.to_string_lossy(), problem)
+    }
+}
 
-    // event sender is allowed to fail (for no receivers)
-    let _e = events.send(PatcherEvent::Progress);
+fn fail_on_name_problems(mut problems: Vec<String>) -> anyhow::Result<()> {
+    if !problems.is_empty() {
+        problems.sort();
+        bail!("Files can't be stored as ICE entries:\n  {}", problems.join("\n  "));
+    }
+    Ok(())
+}
 
+/// Read and validate every file of a patch as the entry it provides.
+fn validate_files(files: &[GroupFiles; 2], validators: &Validators) -> anyhow::Result<()> {
+    for group_files in files.iter() {
+        for (_, file) in group_files.iter() {
+            // entry names have been checked to have an extension
+            let ext = file.name.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+            validators.check_file(&file.path, ext)?;
+        }
+    }
     Ok(())
 }
 
-fn main() {
-    let args = Args::from_args();
+/// Build a new ICE archive from the `1` and `2` group directories in `src`.
+fn pack_directory(src: &Path, format: &IceFormat, validators: &Validators) -> anyhow::Result<IceWriter> {
+    let src_1 = src.join("1");
+    let src_2 = src.join("2");
+    if src_1.exists() && !src_1.is_dir() {
+        bail!("1 in directory {} is not a directory", src.to_string_lossy());
+    }
+    if src_2.exists() && !src_2.is_dir() {
+        bail!("2 in directory {} is not a directory", src.to_string_lossy());
+    }
+    if !src_1.exists() && !src_2.exists() {
+        bail!("Directory {} does not contain a 1 or 2 directory to pack", src.to_string_lossy());
+    }
 
-    if !args.input.exists() {
-        eprintln!("pso2-modpatcher: input patch not found");
-        std::process::exit(1);
+    let files = [GroupFiles::scan(&src_1)?, GroupFiles::scan(&src_2)?];
+    check_entry_names(&[&files[0], &files[1]], true)?;
+    validate_files(&files, validators)?;
+    pack_files(&files, format)
+}
+
+/// Build a new ICE archive from the files of each group, which have already
+/// been checked and validated.
+fn pack_files(files: &[GroupFiles; 2], format: &IceFormat) -> anyhow::Result<IceWriter> {
+    // the v3 encryption of the writer produces archives that don't load
+    if format.version == 3 && format.encrypt {
+        bail!("Encrypted version 3 ICE files are not supported");
     }
-    if args.input.is_file() {
-        eprintln!("pso2-modpatcher: input patch is a file");
-        std::process::exit(1);
+
+    let mut new_ia = IceWriter::new(format.version, format.compress, format.encrypt, false)
+        .with_context(|| "Unable to start creating new ICE archive")?;
+    add_new_files(&mut new_ia, &files[0], Group::Group1, &mut HashSet::new())?;
+    add_new_files(&mut new_ia, &files[1], Group::Group2, &mut HashSet::new())?;
+    Ok(new_ia)
+}
+
+/// Create the missing ICE file `out_file` from the files of its patch alone.
+fn create_ice(patch_src: &Path, out_file: &Path, files: [GroupFiles; 2], ctx: &mut PatchContext) -> anyhow::Result<()> {
+    let format = ctx.new_ice.as_ref().unwrap();
+    eprintln!("{} missing; creating it from {}", out_file.to_string_lossy(), patch_src.to_string_lossy());
+
+    let key = state::key(&ctx.datadir, out_file);
+    let new_ia = pack_files(&files, format)?;
+    if let Some(parent) = out_file.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to make directory {}", parent.to_string_lossy()))?;
     }
-    if !args.datadir.exists() {
-        eprintln!("pso2-modpatcher: output data path does not exist");
-        std::process::exit(1);
+    let patched_hash = write_ice(&new_ia, out_file, patch_src)?;
+
+    let mut record = IceRecord {
+        sources: Vec::new(),
+        filters: BTreeMap::new(),
+        original_hash: None,
+        patched_hash,
+        backup: None,
+        patched_at: state::now(),
+    };
+    add_source(&mut record, patch_src, ctx.filter.as_ref());
+    ctx.state.ices.insert(key, record);
+
+    // event sender is allowed to fail (for no receivers)
+    let _e = ctx.events.send(PatcherEvent::Progress);
+
+    Ok(())
+}
+
+/// Path next to `path` to write its new contents to before moving them into
+/// place, so that the file is replaced atomically and any hard links to the
+/// old file are left intact.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+/// Write a composed ICE archive to `out_file`, returning the hash of the
+/// written file.
+fn write_ice(new_ia: &IceWriter, out_file: &Path, patch_src: &Path) -> anyhow::Result<String> {
+    let partial_file = partial_path(out_file);
+    let new_ia_file = File::create(&partial_file)
+        .with_context(|| format!(
+            "Unable to open ICE file path {} for writing patched archive from {}",
+            partial_file.to_string_lossy(),
+            patch_src.to_string_lossy(),
+        ))?;
+
+    let mut new_ia_sink = HashWriter::new(new_ia_file);
+    new_ia.finish(&mut new_ia_sink)
+        .with_context(|| format!(
+            "Unable to write patched ICE archive to {}",
+            partial_file.to_string_lossy(),
+        ))?;
+    let (new_ia_file, patched_hash) = new_ia_sink.finish();
+    drop(new_ia_file);
+    std::fs::rename(&partial_file, out_file)
+        .with_context(|| format!(
+            "Unable to move patched ICE archive {} into place at {}",
+            partial_file.to_string_lossy(),
+            out_file.to_string_lossy(),
+        ))?;
+    Ok(patched_hash)
+}
+
+/// Re-apply the recorded patch sources of every ICE file that no longer
+/// matches what we last wrote, treating the replaced file as the new original.
+fn reapply(ctx: &mut PatchContext) -> anyhow::Result<()> {
+    let records: Vec<(String, IceRecord)> = ctx.state.ices
+        .iter()
+        .map(|(k, r)| (k.clone(), r.clone()))
+        .collect();
+
+    for (key, record) in records {
+        let ice_path = ctx.datadir.join(&key);
+        if !ice_path.is_file() {
+            eprintln!("{} missing; skipping", ice_path.to_string_lossy());
+            continue;
+        }
+
+        let current_hash = hash::hash_file(&ice_path)
+            .with_context(|| format!("Failed to hash ICE file {}", ice_path.to_string_lossy()))?;
+        if current_hash == record.patched_hash {
+            if ctx.verbose {
+                eprintln!("{} is up to date", ice_path.to_string_lossy());
+            }
+            continue;
+        }
+
+        eprintln!("{} was replaced; reapplying {} patch(es)", ice_path.to_string_lossy(), record.sources.len());
+        for source in record.sources.iter() {
+            ctx.filter = record.filters.get(source).cloned();
+            if let Err(e) = apply_directory(source, &ice_path, ctx)
+                .with_context(|| format!("Failed to reapply {} to {}", source.to_string_lossy(), ice_path.to_string_lossy())) {
+                eprintln!("{:?}\nContinuing...", e);
+                break;
+            }
+        }
+        ctx.filter = None;
     }
-    if args.datadir.is_file() {
-        eprintln!("pso2-modpatcher: output data path is a file");
-        std::process::exit(1);
+
+    Ok(())
+}
+
+/// Print what patching would do for each planned ICE file.
+fn print_plan(plan: &[PatchTarget], ctx: &PatchContext) -> anyhow::Result<()> {
+    for PatchTarget { source, target } in plan {
+        let key = state::key(&ctx.datadir, target);
+        println!("{} (from {})", key, source.to_string_lossy());
+
+        let existing = if !target.is_file() {
+            if ctx.new_ice.is_none() {
+                println!("  missing; would be skipped");
+                continue;
+            }
+            println!("  missing; would be created");
+            None
+        } else {
+            match ice::read_ice(target) {
+                Ok(contents) => Some(contents),
+                Err(e) => {
+                    println!("  does not load: {:#}", e);
+                    continue;
+                },
+            }
+        };
+
+        let (files, extracted) = match ctx.scan_patch(source) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("  can't be patched: {:#}", e);
+                continue;
+            },
+        };
+        let swap = match extracted.as_ref().map(|_| Swap::load(source)).transpose() {
+            Ok(swap) => swap.flatten(),
+            Err(e) => {
+                println!("  can't be patched: {:#}", e);
+                continue;
+            },
+        };
+        let rename = match RenameRules::load(source) {
+            Ok(rename) => rename,
+            Err(e) => {
+                println!("  can't be patched: {:#}", e);
+                continue;
+            },
+        };
+        for &group in ice::GROUPS.iter() {
+            let group_num = ice::group_index(group) + 1;
+            // names of the original entries once renamed
+            let mut entry_names = HashSet::new();
+            for entry in existing.iter().flat_map(|c| c.group(group)) {
+                let renamed = match &rename {
+                    Some(rename) => rename.apply(&entry.name),
+                    None => entry.name.as_str().into(),
+                };
+                if renamed != entry.name {
+                    println!("  rename group {} {} to {}", group_num, entry.name, renamed);
+                }
+                entry_names.insert(renamed.to_lowercase());
+            }
+
+            for (name_key, file) in files[ice::group_index(group)].iter() {
+                let action = if ctx.filter.as_ref().is_some_and(|f| !f.includes(&key, group_num, &file.name)) {
+                    "exclude"
+                } else if entry_names.contains(name_key) {
+                    "replace"
+                } else {
+                    "add"
+                };
+                let file_name = file.path.file_name().unwrap().to_string_lossy();
+                // copied entries are described by the name they have in the
+                // swap's source, before the swap or rename rules renamed them
+                let source_name = extracted.as_ref().and_then(|e| e.source_name(&file.path));
+                match (&swap, source_name) {
+                    (Some(swap), Some(source_name)) if source_name != file.name => {
+                        println!("  {} group {} {} (from {} entry {})", action, group_num, file.name, swap.source, source_name);
+                    },
+                    (Some(swap), Some(_)) => {
+                        println!("  {} group {} {} (from {})", action, group_num, file.name, swap.source);
+                    },
+                    _ if file_name != file.name => {
+                        println!("  {} group {} {} (from {})", action, group_num, file.name, file.path.to_string_lossy());
+                    },
+                    _ => println!("  {} group {} {}", action, group_num, file.name),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Directory within the data directory that a profile switch moves the ICE
+/// files it replaces into, until it has succeeded or been undone.
+const REPLACED_DIR_NAME: &str = ".modpatcher-replaced";
+
+/// Rebuild the ICE files whose patches differ between what is installed and
+/// `patches`, so that exactly `patches` are applied to the data directory.
+///
+/// Every changed ICE file is built from its original in a staging directory
+/// first, and only once all are built are they moved into place. If anything
+/// fails to build or to be moved into place, the data directory is left as it
+/// was.
+fn switch_patches(ctx: &mut PatchContext, patches: &[PathBuf]) -> anyhow::Result<()> {
+    // the `_ice` sources to apply to each ICE file, in order
+    let mut wanted: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for patch in patches {
+        if !patch.is_dir() {
+            bail!("Patch path {} is not a directory", patch.to_string_lossy());
+        }
+        // packages are installed with the parts last chosen for them
+        let manifest = Manifest::load(patch)?;
+        let selection = match &manifest {
+            Some(manifest) => {
+                let package = std::fs::canonicalize(patch).unwrap_or_else(|_| patch.clone());
+                Some(manifest.select(ctx.state.packages.get(&package), &[], &[], &[])?)
+            },
+            None => None,
+        };
+        let (plan, _expanded) = plan_patch(
+            patch,
+            &ctx.datadir,
+            manifest.as_ref().zip(selection.as_ref()),
+            &ctx.backup_dir,
+            &ctx.state,
+            false,
+            ctx.verbose,
+        )?;
+        for PatchTarget { source, target } in plan {
+            let source = std::fs::canonicalize(&source).unwrap_or(source);
+            wanted.entry(state::key(&ctx.datadir, &target)).or_default().push(source);
+        }
+    }
+
+    let mut keys: BTreeSet<String> = wanted.keys().cloned().collect();
+    keys.extend(ctx.state.ices.keys().cloned());
+    let mut changed = Vec::new();
+    for key in keys {
+        let sources = wanted.get(&key).map(|s| s.as_slice()).unwrap_or(&[]);
+        let ice_path = ctx.datadir.join(&key);
+        match ctx.state.ices.get(&key) {
+            Some(record) => {
+                let current_hash = if ice_path.is_file() {
+                    Some(hash::hash_file(&ice_path)
+                        .with_context(|| format!("Failed to hash ICE file {}", ice_path.to_string_lossy()))?)
+                } else {
+                    None
+                };
+                if current_hash.as_ref() != Some(&record.patched_hash) {
+                    bail!(
+                        "{} changed since it was last patched; run reapply before switching",
+                        ice_path.to_string_lossy(),
+                    );
+                }
+                if record.sources == sources && record.filters.is_empty() {
+                    continue;
+                }
+                if record.original_hash.is_some() && !record.backup.as_ref().is_some_and(|b| b.is_file()) {
+                    bail!("{} has no backup of its original to rebuild it from", ice_path.to_string_lossy());
+                }
+            },
+            None if sources.is_empty() => continue,
+            None => {},
+        }
+        changed.push(key);
+    }
+
+    if changed.is_empty() {
+        eprintln!("Every ICE file already has the right patches");
+        return Ok(());
+    }
+    eprintln!("Rebuilding {} ICE file(s)", changed.len());
+
+    let replaced = ctx.datadir.join(REPLACED_DIR_NAME);
+    if replaced.exists() {
+        bail!(
+            "{} holds ICE files from a switch that could not be undone; move them back into the data directory and remove it first",
+            replaced.to_string_lossy(),
+        );
     }
+    rebuild_changed(ctx, &changed, &wanted)
+}
+
+/// Build each of `changed` with the sources `wanted` for it in a staging
+/// directory, then move them into place with [`commit_staged`].
+fn rebuild_changed(ctx: &mut PatchContext, changed: &[String], wanted: &BTreeMap<String, Vec<PathBuf>>) -> anyhow::Result<()> {
+    let staging = ctx.datadir.join(".modpatcher-switch");
+    if staging.exists() {
+        // left over from an interrupted switch
+        std::fs::remove_dir_all(&staging)
+            .with_context(|| format!("Failed to remove staging directory {}", staging.to_string_lossy()))?;
+    }
+
+    // build in the staging directory with a state of its own, so that the
+    // records of the real data directory are only touched once all succeed
+    let datadir = std::mem::replace(&mut ctx.datadir, staging.clone());
+    let state = std::mem::take(&mut ctx.state);
+    let backups = ctx.backups.take();
+    ctx.staged_from = Some((datadir.clone(), state.clone()));
+    let staged = stage_patches(ctx, changed, wanted, &datadir, &state);
+    ctx.staged_from = None;
+    ctx.datadir = datadir;
+    let staged_state = std::mem::replace(&mut ctx.state, state);
+    ctx.backups = backups;
 
+    let result = staged.and_then(|()| commit_staged(ctx, changed, &staging, staged_state));
+    // best effort; a leftover staging directory is removed on the next switch
+    let _e = std::fs::remove_dir_all(&staging);
+    result
+}
+
+/// Build each of `keys` in the staging directory `ctx.datadir`, starting
+/// from the original file.
+fn stage_patches(
+    ctx: &mut PatchContext,
+    keys: &[String],
+    wanted: &BTreeMap<String, Vec<PathBuf>>,
+    datadir: &Path,
+    state: &InstallState,
+) -> anyhow::Result<()> {
+    for key in keys {
+        let staged_file = ctx.datadir.join(key);
+        let staged_parent = staged_file.parent().unwrap();
+        std::fs::create_dir_all(staged_parent)
+            .with_context(|| format!("Failed to make staging directory {}", staged_parent.to_string_lossy()))?;
+
+        let original = match state.ices.get(key) {
+            Some(record) => record.backup.clone(),
+            None => Some(datadir.join(key)).filter(|p| p.is_file()),
+        };
+        if let Some(original) = original {
+            std::fs::copy(&original, &staged_file)
+                .with_context(|| format!("Failed to copy original {} for rebuilding", original.to_string_lossy()))?;
+        }
+
+        // ICE files we created are created again, even without --create-missing
+        let created = state.ices.get(key).is_some_and(|r| r.original_hash.is_none());
+        let new_ice = ctx.new_ice.clone();
+        if created && new_ice.is_none() {
+            ctx.new_ice = Some(IceFormat { version: 4, encrypt: false, compress: false });
+        }
+        let result = wanted.get(key).into_iter().flatten().try_for_each(|source| {
+            apply_directory(source, &staged_file, ctx)
+                .with_context(|| format!("Failed to apply {} to {}", source.to_string_lossy(), key))
+        });
+        ctx.new_ice = new_ice;
+        result?;
+    }
+    Ok(())
+}
+
+/// Move the ICE files built by [`stage_patches`] into the data directory,
+/// backing up originals patched for the first time.
+///
+/// Originals are backed up first, linked or copied so that the data directory
+/// is untouched until all are. The files being replaced are then moved aside
+/// as the rebuilt files are moved in, and if any move fails, every move made
+/// is undone. The install state is only updated once all have succeeded.
+fn commit_staged(ctx: &mut PatchContext, keys: &[String], staging: &Path, mut staged_state: InstallState) -> anyhow::Result<()> {
+    let mut backups = HashMap::new();
+    if let Some(store) = &mut ctx.backups {
+        for key in keys {
+            if ctx.state.ices.contains_key(key) || !staging.join(key).is_file() {
+                continue;
+            }
+            if let Some(original_hash) = staged_state.ices.get(key).and_then(|r| r.original_hash.as_ref()) {
+                let backup = store.back_up_copy(&ctx.datadir.join(key), key, original_hash, ctx.verbose)?;
+                backups.insert(key.as_str(), backup);
+            }
+        }
+    }
+
+    let replaced = ctx.datadir.join(REPLACED_DIR_NAME);
+    let mut moves = Vec::new();
+    if let Err(e) = move_staged(&ctx.datadir, staging, &replaced, keys, &mut moves) {
+        return match undo_moves(&ctx.datadir, staging, &replaced, &moves) {
+            Ok(()) => {
+                // best effort; it is empty
+                let _e = std::fs::remove_dir_all(&replaced);
+                Err(e.context("Failed to move the rebuilt ICE files into place; the data directory is unchanged"))
+            },
+            Err(undo) => Err(e.context(format!(
+                "Failed to move the rebuilt ICE files into place, then to undo that ({:#}); the ICE files replaced are in {}",
+                undo,
+                replaced.to_string_lossy(),
+            ))),
+        };
+    }
+    if let Err(e) = std::fs::remove_dir_all(&replaced) {
+        eprintln!("Warning: failed to remove {}: {}", replaced.to_string_lossy(), e);
+    }
+
+    for key in keys {
+        let old = ctx.state.ices.remove(key);
+        if let Some(new) = staged_state.ices.remove(key) {
+            let backup = match &old {
+                Some(old) => old.backup.clone(),
+                None => backups.remove(key.as_str()),
+            };
+            ctx.state.ices.insert(key.clone(), IceRecord {
+                original_hash: old.map(|r| r.original_hash).unwrap_or(new.original_hash),
+                backup,
+                ..new
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A file moved into the data directory by [`move_staged`].
+struct StagedMove<'a> {
+    key: &'a str,
+    /// Whether the file it replaces was moved aside.
+    replaced: bool,
+    /// Whether the rebuilt file was moved into place.
+    placed: bool,
+}
+
+/// Move each of `keys` in the data directory aside into `replaced`, and the
+/// rebuilt file in `staging`, if any, into its place, recording each move.
+fn move_staged<'a>(datadir: &Path, staging: &Path, replaced: &Path, keys: &'a [String], moves: &mut Vec<StagedMove<'a>>) -> anyhow::Result<()> {
+    for key in keys {
+        let ice_path = datadir.join(key);
+        let staged_file = staging.join(key);
+        moves.push(StagedMove { key, replaced: false, placed: false });
+        let moved = moves.last_mut().unwrap();
+
+        if ice_path.is_file() {
+            let aside = replaced.join(key);
+            let aside_parent = aside.parent().unwrap();
+            std::fs::create_dir_all(aside_parent)
+                .with_context(|| format!("Failed to make directory {}", aside_parent.to_string_lossy()))?;
+            std::fs::rename(&ice_path, &aside)
+                .with_context(|| format!("Failed to move {} aside", ice_path.to_string_lossy()))?;
+            moved.replaced = true;
+        }
+        // without a rebuilt file, the ICE was created by us for a patch no
+        // longer wanted
+        if staged_file.is_file() {
+            std::fs::rename(&staged_file, &ice_path)
+                .with_context(|| format!("Failed to move rebuilt {} into place", ice_path.to_string_lossy()))?;
+            moved.placed = true;
+        }
+    }
+    Ok(())
+}
+
+/// Undo the moves made by [`move_staged`], latest first.
+fn undo_moves(datadir: &Path, staging: &Path, replaced: &Path, moves: &[StagedMove]) -> anyhow::Result<()> {
+    for moved in moves.iter().rev() {
+        let ice_path = datadir.join(moved.key);
+        if moved.placed {
+            std::fs::rename(&ice_path, staging.join(moved.key))
+                .with_context(|| format!("Failed to move rebuilt {} back out", ice_path.to_string_lossy()))?;
+        }
+        if moved.replaced {
+            std::fs::rename(replaced.join(moved.key), &ice_path)
+                .with_context(|| format!("Failed to move {} back into place", ice_path.to_string_lossy()))?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::from_args();
+
+    #[cfg_attr(not(windows), allow(unused_variables))]
     let (tx, rx) = mpsc::channel::<PatcherEvent>();
 
     #[cfg(windows)]
@@ -503,20 +1543,1129 @@ fn main() {
         drop(rx);
     }
 
-    let backup_dir;
-    if args.no_backup {
-        backup_dir = None;
-    } else {
-        backup_dir = Some(args.datadir.join("backup"));
+    if let Err(e) = run(&args, tx) {
+        eprintln!("pso2-modpatcher: {:?}", e);
+        std::process::exit(1);
     }
+}
 
+/// Carry out the command given on the command line.
+fn run(args: &Args, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
+    match args.command {
+        Command::Apply(ref apply) => run_patch(args, apply, events),
+        Command::Reapply { ref datadir } => run_reapply(args, datadir, events),
+        Command::Verify { ref datadir } => run_verify(args, datadir),
+        Command::Status { ref datadir } => run_status(datadir),
+        Command::Profile(ProfileCommand::List { ref datadir }) => run_profile_list(datadir),
+        Command::Profile(ProfileCommand::Set { ref datadir, ref name, ref patches }) => run_profile_set(datadir, name, patches),
+        Command::Profile(ProfileCommand::Switch { ref datadir, ref name }) => run_profile_switch(args, datadir, name, events),
+        Command::Gc { ref datadir } => run_gc(args, datadir),
+        Command::Index { ref datadir } => run_index(args, datadir),
+        Command::Find { ref datadir, ref pattern } => run_find(datadir, pattern),
+        Command::Diff { ref a, ref b, json } => run_diff(a, b, json),
+        Command::MakePatch { ref original, ref modified, ref out, ref generation } => {
+            run_make_patch(args, original, modified, out, generation.as_deref())
+        },
+        Command::Pack { ref dir, ref out } => run_pack(args, dir, out),
+        Command::Lint { ref input } => run_lint(input),
+    }
+}
+
+fn check_datadir(datadir: &Path) {
+    if !datadir.exists() {
+        eprintln!("pso2-modpatcher: output data path does not exist");
+        std::process::exit(1);
+    }
+    if datadir.is_file() {
+        eprintln!("pso2-modpatcher: output data path is a file");
+        std::process::exit(1);
+    }
+}
+
+fn run_patch(args: &Args, apply: &ApplyArgs, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
+    let (input, datadir) = (&apply.input, &apply.datadir);
+
+    if !input.exists() {
+        eprintln!("pso2-modpatcher: input patch not found");
+        std::process::exit(1);
+    }
+    if input.is_file() {
+        eprintln!("pso2-modpatcher: input patch is a file");
+        std::process::exit(1);
+    }
+    check_datadir(datadir);
+
+    for finding in lint::lint(input)? {
+        eprintln!("Warning: {}", finding);
+    }
+
+    let mut ctx = PatchContext {
+        datadir: datadir.clone(),
+        verbose: args.verbose,
+        events,
+        state: InstallState::load(datadir)?,
+        new_ice: if args.create_missing { Some(args.new_ice.clone()) } else { None },
+        validators: Validators::new(args.validate),
+        // opening the cache creates its directory, and a dry run reads nothing from it
+        cache: if apply.dry_run { None } else { args.cache_dir.as_deref().map(PatchCache::open).transpose()? },
+        backups: if apply.dry_run { None } else { open_backups(args, datadir)? },
+        backup_dir: backup_dir(args, datadir),
+        filter: PatchFilter::new(&apply.include, &apply.exclude)?,
+        staged_from: None,
+    };
+
+    let manifest = Manifest::load(input)?;
+    let selection = match &manifest {
+        Some(manifest) => {
+            let package = std::fs::canonicalize(input)
+                .with_context(|| format!("Failed to resolve patch path {}", input.to_string_lossy()))?;
+            let previous = ctx.state.packages.get(&package);
+            let selection = manifest.select(previous, &apply.with, &apply.without, &apply.variants)?;
+            if !manifest.components.is_empty() || !manifest.variants.is_empty() {
+                eprintln!("Installing {}", selection.describe());
+            }
+            if previous.is_some_and(|p| manifest.selected_paths(p).any(|a| manifest.selected_paths(&selection).all(|b| a != b))) {
+                eprintln!(
+                    "Warning: parts of {} installed before are not removed by patching; \
+                    switch to a profile with it to rebuild from the originals",
+                    input.to_string_lossy(),
+                );
+            }
+            Some((package, selection))
+        },
+        None if !apply.with.is_empty() || !apply.without.is_empty() || !apply.variants.is_empty() => {
+            bail!("{} has no {} declaring components or variants", input.to_string_lossy(), manifest::MANIFEST_FILE_NAME);
+        },
+        None => None,
+    };
+    // expanded entries replaced everywhere are only removed after printing a
+    // dry run
+    let (plan, _expanded) = plan_patch(
+        input,
+        datadir,
+        manifest.as_ref().zip(selection.as_ref().map(|(_, s)| s)),
+        &ctx.backup_dir,
+        &ctx.state,
+        apply.dry_run,
+        ctx.verbose,
+    )?;
+
+    if apply.dry_run {
+        return print_plan(&plan, &ctx);
+    }
+
+    if let Some((package, selection)) = selection {
+        ctx.state.packages.insert(package, selection);
+    }
     // apply_directory(&args.input, &args.datadir)?;
-    match iterate_patch_directory(&args.input, &args.datadir, backup_dir.as_ref().map(|v| v.as_path()), args.verbose, tx.clone()) {
-        Ok(_) => {},
+    let result = iterate_patch_directory(plan, &mut ctx);
+    ctx.state.save(datadir)?;
+    if let Some(backups) = &ctx.backups {
+        backups.save()?;
+    }
+    result?;
+    prune_backups(args, &mut ctx)
+}
+
+fn run_reapply(args: &Args, datadir: &Path, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let mut ctx = PatchContext {
+        datadir: datadir.to_path_buf(),
+        verbose: args.verbose,
+        events,
+        state: InstallState::load(datadir)?,
+        new_ice: None,
+        validators: Validators::new(args.validate),
+        cache: args.cache_dir.as_deref().map(PatchCache::open).transpose()?,
+        backups: open_backups(args, datadir)?,
+        backup_dir: backup_dir(args, datadir),
+        filter: None,
+        staged_from: None,
+    };
+
+    let result = reapply(&mut ctx);
+    ctx.state.save(datadir)?;
+    if let Some(backups) = &ctx.backups {
+        backups.save()?;
+    }
+    result?;
+    prune_backups(args, &mut ctx)
+}
+
+fn open_backups(args: &Args, datadir: &Path) -> anyhow::Result<Option<BackupStore>> {
+    if args.no_backup {
+        return Ok(None);
+    }
+    BackupStore::new(&backup_dir(args, datadir), args.backup_link).map(Some)
+}
+
+/// The directory originals from `datadir` are backed up to.
+fn backup_dir(args: &Args, datadir: &Path) -> PathBuf {
+    args.backup_dir.clone().unwrap_or_else(|| datadir.join("backup"))
+}
+
+fn prune_backups(args: &Args, ctx: &mut PatchContext) -> anyhow::Result<()> {
+    match (&mut ctx.backups, args.keep) {
+        (Some(backups), Some(keep)) => backups.prune(keep, &ctx.state, args.verbose),
+        _ => Ok(()),
+    }
+}
+
+fn run_profile_list(datadir: &Path) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let profiles = Profiles::load(datadir)?;
+    let state = InstallState::load(datadir)?;
+    if profiles.profiles.is_empty() {
+        println!("No profiles have been made for {}", datadir.to_string_lossy());
+    }
+    for (name, patches) in profiles.profiles.iter() {
+        let active = state.profile.as_ref() == Some(name);
+        println!("{}{}", name, if active { " (active)" } else { "" });
+        for patch in patches {
+            println!("  {}", patch.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+fn run_profile_set(datadir: &Path, name: &str, patches: &[PathBuf]) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let mut sources = Vec::new();
+    for patch in patches {
+        if !patch.is_dir() {
+            bail!("Patch path {} is not a directory", patch.to_string_lossy());
+        }
+        sources.push(std::fs::canonicalize(patch)
+            .with_context(|| format!("Failed to resolve patch path {}", patch.to_string_lossy()))?);
+    }
+    let mut profiles = Profiles::load(datadir)?;
+    profiles.profiles.insert(name.to_owned(), sources);
+    profiles.save(datadir)
+}
+
+fn run_profile_switch(args: &Args, datadir: &Path, name: &str, events: mpsc::Sender<PatcherEvent>) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let profiles = Profiles::load(datadir)?;
+    let patches = match profiles.profiles.get(name) {
+        Some(p) => p,
+        None => bail!("No profile named {:?}", name),
+    };
+
+    let mut ctx = PatchContext {
+        datadir: datadir.to_path_buf(),
+        verbose: args.verbose,
+        events,
+        state: InstallState::load(datadir)?,
+        new_ice: if args.create_missing { Some(args.new_ice.clone()) } else { None },
+        validators: Validators::new(args.validate),
+        cache: args.cache_dir.as_deref().map(PatchCache::open).transpose()?,
+        backups: open_backups(args, datadir)?,
+        backup_dir: backup_dir(args, datadir),
+        filter: None,
+        staged_from: None,
+    };
+
+    // originals may have been backed up before a failure, so the backup index
+    // is saved either way
+    let result = switch_patches(&mut ctx, patches);
+    if result.is_ok() {
+        ctx.state.profile = Some(name.to_owned());
+    }
+    ctx.state.save(datadir)?;
+    if let Some(backups) = &ctx.backups {
+        backups.save()?;
+    }
+    result?;
+    prune_backups(args, &mut ctx)
+}
+
+fn run_status(datadir: &Path) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let state = InstallState::load(datadir)?;
+    status::status(datadir, &state, &mut std::io::stdout().lock())
+}
+
+fn run_gc(args: &Args, datadir: &Path) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let state = InstallState::load(datadir)?;
+    let backup_dir = backup_dir(args, datadir);
+    let backups = match BackupStore::open_existing(&backup_dir)? {
+        Some(backups) => backups,
+        None => {
+            eprintln!("There are no backups in {}", backup_dir.to_string_lossy());
+            return Ok(());
+        },
+    };
+    let (count, bytes) = backups.gc(&state, args.verbose)?;
+    eprintln!("Removed {} unreferenced backups ({:.1} MiB)", count, bytes as f64 / (1024.0 * 1024.0));
+    Ok(())
+}
+
+fn run_verify(args: &Args, datadir: &Path) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let state = InstallState::load(datadir)?;
+    let drifted = verify::verify(datadir, &state, args.verbose)?;
+    if drifted > 0 {
+        bail!("{} of {} patched ICE files have drifted", drifted, state.ices.len());
+    }
+    eprintln!("All {} patched ICE files are intact", state.ices.len());
+    Ok(())
+}
+
+fn run_index(args: &Args, datadir: &Path) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let previous = match EntryIndex::load(datadir) {
+        Ok(p) => p,
         Err(e) => {
-            
-            eprintln!("pso2-modpatcher: {}", e);
-            return;
+            eprintln!("Warning: rebuilding the index from scratch: {:#}", e);
+            None
         },
+    };
+    let (index, stats) = EntryIndex::build(datadir, &backup_dir(args, datadir), previous, args.verbose)?;
+    index.save(datadir)?;
+    let entries: usize = index.ices.values().map(|i| i.entries.len()).sum();
+    eprintln!(
+        "Indexed {} entries of {} ICE files ({} read, {} unchanged)",
+        entries,
+        index.ices.len(),
+        stats.read,
+        stats.reused,
+    );
+    if stats.failed > 0 {
+        eprintln!("{} ICE files could not be read; run with -v to list them", stats.failed);
+    }
+    Ok(())
+}
+
+fn run_find(datadir: &Path, pattern: &str) -> anyhow::Result<()> {
+    check_datadir(datadir);
+
+    let index = match EntryIndex::load(datadir)? {
+        Some(i) => i,
+        None => bail!("{} has not been indexed; run index first", datadir.to_string_lossy()),
+    };
+    let found = index.find(pattern)?;
+    for (key, entry) in found.iter() {
+        println!("{}: group {} {} ({} bytes, {})", key, entry.group, entry.name, entry.size, entry.hash);
+    }
+    if found.is_empty() {
+        eprintln!("No entries match {:?}", pattern);
+    }
+    eprintln!("Index built {}", state::format_time(index.indexed_at));
+    Ok(())
+}
+
+fn run_diff(a: &Path, b: &Path, json: bool) -> anyhow::Result<()> {
+    let ice_a = ice::read_ice(a)?;
+    let ice_b = ice::read_ice(b)?;
+    let diff = diff::diff_ice(&ice_a, &ice_b);
+    if json {
+        let out = serde_json::to_string_pretty(&diff)
+            .with_context(|| "Failed to serialize diff")?;
+        println!("{}", out);
+    } else {
+        diff::print_diff(&diff);
+    }
+    Ok(())
+}
+
+fn run_make_patch(args: &Args, original: &Path, modified: &Path, out: &Path, generation: Option<&str>) -> anyhow::Result<()> {
+    if !original.is_dir() {
+        bail!("Original data directory {} is not a directory", original.to_string_lossy());
+    }
+    if !modified.is_dir() {
+        bail!("Modified data directory {} is not a directory", modified.to_string_lossy());
+    }
+    if out.exists() && out.read_dir().map_or(true, |mut d| d.next().is_some()) {
+        bail!("Patch output directory {} already exists and is not empty", out.to_string_lossy());
+    }
+
+    let backups = BackupStore::open_existing(original)?;
+    let originals = match (&backups, generation) {
+        (Some(backups), Some(generation)) if !backups.has_generation(generation) => {
+            let generations: Vec<&str> = backups.generations().collect();
+            bail!("{} has no backup generation {}; it has {}", original.to_string_lossy(), generation, generations.join(", "));
+        },
+        (Some(backups), generation) => {
+            eprintln!("Reading originals from the backups in {}", original.to_string_lossy());
+            make_patch::Originals::Backup(backups, generation)
+        },
+        (None, Some(_)) => bail!("{} is not a backup directory, so has no generations", original.to_string_lossy()),
+        (None, None) => make_patch::Originals::Datadir(original),
+    };
+    let count = make_patch::make_patch(&originals, modified, &backup_dir(args, modified), out, args.verbose)?;
+    eprintln!("Wrote patches for {} ICE files to {}", count, out.to_string_lossy());
+    Ok(())
+}
+
+fn run_pack(args: &Args, dir: &Path, out: &Path) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.to_string_lossy());
+    }
+
+    let new_ia = pack_directory(dir, &args.new_ice, &Validators::new(args.validate))?;
+    write_ice(&new_ia, out, dir)?;
+    Ok(())
+}
+
+fn run_lint(input: &Path) -> anyhow::Result<()> {
+    if !input.is_dir() {
+        bail!("Patch path {} is not a directory", input.to_string_lossy());
+    }
+
+    let findings = lint::lint(input)?;
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+    if !findings.is_empty() {
+        bail!("{} problems found", findings.len());
+    }
+    eprintln!("No problems found");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("pso2-modpatcher-test-{}-{}", std::process::id(), name));
+            let _e = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _e = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Write a version 4 ICE file at `out` with the group 1 entries `files`.
+    fn make_ice(tmp: &Path, out: &Path, files: &[(&str, &[u8])]) {
+        let src = tmp.join("ice-src");
+        for (name, contents) in files {
+            write(&src.join("1").join(name), contents);
+        }
+        // packed without checks, to make archives patches couldn't
+        let format = IceFormat { version: 4, encrypt: false, compress: false };
+        let files = [GroupFiles::scan(&src.join("1")).unwrap(), GroupFiles::default()];
+        let new_ia = pack_files(&files, &format).unwrap();
+        std::fs::create_dir_all(out.parent().unwrap()).unwrap();
+        write_ice(&new_ia, out, &src).unwrap();
+        std::fs::remove_dir_all(&src).unwrap();
+    }
+
+    fn context(datadir: &Path, validate: ValidationLevel, backup_dir: &Path) -> PatchContext {
+        PatchContext {
+            datadir: datadir.to_path_buf(),
+            verbose: false,
+            events: mpsc::channel().0,
+            state: InstallState::default(),
+            new_ice: None,
+            validators: Validators::new(validate),
+            cache: None,
+            backups: Some(BackupStore::new(backup_dir, None).unwrap()),
+            backup_dir: backup_dir.to_path_buf(),
+            filter: None,
+            staged_from: None,
+        }
+    }
+
+    #[test]
+    fn refused_files_leave_the_ice_untouched() {
+        let tmp = TempDir::new("validate-error");
+        let datadir = tmp.0.join("data");
+        let ice_path = datadir.join("win32").join("target");
+        make_ice(&tmp.0, &ice_path, &[("model.aqp", b"NIFL model"), ("tex.dds", b"not a texture")]);
+        let original_hash = hash::hash_file(&ice_path).unwrap();
+
+        // the first file is fine, so only the second one is refused
+        let patch_src = tmp.0.join("patch").join("win32").join("target_ice");
+        write(&patch_src.join("1").join("a.aqp"), b"NIFL new model");
+        write(&patch_src.join("1").join("model.aqp"), b"no magic");
+
+        let backup_dir = tmp.0.join("backup");
+        let mut ctx = context(&datadir, ValidationLevel::Error, &backup_dir);
+        let e = apply_directory(&patch_src, &ice_path, &mut ctx).unwrap_err();
+        assert!(format!("{:#}", e).contains("failed validation"), "{:#}", e);
+
+        assert_eq!(hash::hash_file(&ice_path).unwrap(), original_hash);
+        assert!(ctx.state.ices.is_empty());
+        assert!(!backup_dir.join("blobs").exists());
+    }
+
+    #[test]
+    fn warnings_still_patch() {
+        let tmp = TempDir::new("validate-warn");
+        let datadir = tmp.0.join("data");
+        let ice_path = datadir.join("win32").join("target");
+        make_ice(&tmp.0, &ice_path, &[("model.aqp", b"NIFL model")]);
+
+        let patch_src = tmp.0.join("patch").join("win32").join("target_ice");
+        write(&patch_src.join("1").join("model.aqp"), b"no magic");
+
+        let mut ctx = context(&datadir, ValidationLevel::Warn, &tmp.0.join("backup"));
+        apply_directory(&patch_src, &ice_path, &mut ctx).unwrap();
+
+        let record = ctx.state.ices.get("win32/target").unwrap();
+        assert!(record.backup.as_ref().is_some_and(|b| b.is_file()));
+        let patched = ice::read_ice(&ice_path).unwrap();
+        assert_eq!(patched.group(Group::Group1)[0].data, b"no magic");
+    }
+
+    #[test]
+    fn only_added_entries_are_held_to_the_name_length_limit() {
+        let tmp = TempDir::new("name-length");
+        let datadir = tmp.0.join("data");
+        let ice_path = datadir.join("win32").join("target");
+        let long_name = format!("{}.dds", "a".repeat(ice::NEW_NAME_LEN));
+        make_ice(&tmp.0, &ice_path, &[(&long_name, b"old")]);
+        let original_hash = hash::hash_file(&ice_path).unwrap();
+
+        // adding an entry with a name as long is refused before anything is
+        // backed up
+        let patch_src = tmp.0.join("patch").join("win32").join("target_ice");
+        let added_name = format!("b{}", long_name);
+        write(&patch_src.join("1").join(&added_name), b"new");
+        let backup_dir = tmp.0.join("backup");
+        let mut ctx = context(&datadir, ValidationLevel::Off, &backup_dir);
+        let e = apply_directory(&patch_src, &ice_path, &mut ctx).unwrap_err();
+        assert!(format!("{:#}", e).contains(&added_name), "{:#}", e);
+        assert_eq!(hash::hash_file(&ice_path).unwrap(), original_hash);
+        assert!(!backup_dir.join("blobs").exists());
+
+        // replacing the entry already there is fine
+        std::fs::remove_file(patch_src.join("1").join(&added_name)).unwrap();
+        write(&patch_src.join("1").join(long_name.to_uppercase()), b"new");
+        apply_directory(&patch_src, &ice_path, &mut ctx).unwrap();
+        let patched = ice::read_ice(&ice_path).unwrap();
+        assert_eq!(patched.group(Group::Group1)[0].name, long_name);
+        assert_eq!(patched.group(Group::Group1)[0].data, b"new");
+    }
+
+    #[test]
+    fn swaps_copy_from_the_original_of_a_patched_source() {
+        let tmp = TempDir::new("swap-original");
+        let datadir = tmp.0.join("data");
+        let source_path = datadir.join("win32").join("source");
+        let target_path = datadir.join("win32").join("target");
+        make_ice(&tmp.0, &source_path, &[("a.aqp", b"NIFL original")]);
+        make_ice(&tmp.0, &target_path, &[("b.aqp", b"NIFL target")]);
+
+        let patch = tmp.0.join("patch").join("win32");
+        write(&patch.join("source_ice").join("1").join("a.aqp"), b"NIFL patched");
+        write(&patch.join("target_ice").join(swap::SWAP_FILE_NAME), b"source = \"win32/source\"\n");
+
+        let backup_dir = tmp.0.join("backup");
+        let mut ctx = context(&datadir, ValidationLevel::Off, &backup_dir);
+        apply_directory(&patch.join("source_ice"), &source_path, &mut ctx).unwrap();
+        apply_directory(&patch.join("target_ice"), &target_path, &mut ctx).unwrap();
+        let target = ice::read_ice(&target_path).unwrap();
+        let swapped = target.group(Group::Group1).iter().find(|e| e.name == "a.aqp").unwrap();
+        assert_eq!(swapped.data, b"NIFL original");
+    }
+
+    #[test]
+    fn renames_are_checked_before_anything_is_written() {
+        let tmp = TempDir::new("rename");
+        let datadir = tmp.0.join("data");
+        let ice_path = datadir.join("win32").join("target");
+        make_ice(&tmp.0, &ice_path, &[("pl_123.aqp", b"NIFL model"), ("pl_123.dds", b"DDS texture")]);
+        let original_hash = hash::hash_file(&ice_path).unwrap();
+
+        // only rename rules, and one giving an entry too long an extension
+        let patch_src = tmp.0.join("patch").join("win32").join("target_ice");
+        write(&patch_src.join(rename::RENAME_FILE_NAME), b"[[rule]]\nfrom = \".aqp\"\nto = \".aqpxx\"\n");
+
+        let backup_dir = tmp.0.join("backup");
+        let mut ctx = context(&datadir, ValidationLevel::Off, &backup_dir);
+        let e = apply_directory(&patch_src, &ice_path, &mut ctx).unwrap_err();
+        assert!(format!("{:#}", e).contains("pl_123.aqpxx"), "{:#}", e);
+        assert_eq!(hash::hash_file(&ice_path).unwrap(), original_hash);
+        assert!(!backup_dir.join("blobs").exists());
+
+        write(&patch_src.join(rename::RENAME_FILE_NAME), b"[[rule]]\nfrom = \"_123.\"\nto = \"_456.\"\n");
+        apply_directory(&patch_src, &ice_path, &mut ctx).unwrap();
+        let patched = ice::read_ice(&ice_path).unwrap();
+        let names: Vec<_> = patched.group(Group::Group1).iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["pl_456.aqp", "pl_456.dds"]);
+    }
+
+    #[test]
+    fn a_bad_target_does_not_end_a_dry_run() {
+        let tmp = TempDir::new("dry-run-bad-rules");
+        let datadir = tmp.0.join("data");
+        make_ice(&tmp.0, &datadir.join("win32").join("bad"), &[("a.aqp", b"NIFL a")]);
+        make_ice(&tmp.0, &datadir.join("win32").join("good"), &[("b.aqp", b"NIFL b")]);
+
+        let patch = tmp.0.join("patch").join("win32");
+        write(&patch.join("bad_ice").join(rename::RENAME_FILE_NAME), b"[[rule]]\nregex = \"(\"\nto = \"x\"\n");
+        write(&patch.join("good_ice").join("1").join("b.aqp"), b"NIFL new b");
+        let plan: Vec<PatchTarget> = ["bad", "good"].iter()
+            .map(|name| PatchTarget {
+                source: patch.join(format!("{}_ice", name)),
+                target: datadir.join("win32").join(name),
+            })
+            .collect();
+
+        let ctx = context(&datadir, ValidationLevel::Off, &tmp.0.join("backup"));
+        print_plan(&plan, &ctx).unwrap();
+    }
+
+    #[test]
+    fn indexing_skips_the_backup_directory() {
+        let tmp = TempDir::new("index-backup-dir");
+        let datadir = tmp.0.join("data");
+        make_ice(&tmp.0, &datadir.join("win32").join("aaa"), &[("a.aqp", b"NIFL a")]);
+        // backups kept within the data directory under another name, and a
+        // stale copy in a directory of our own
+        make_ice(&tmp.0, &datadir.join("old-backups").join("blobs").join("ab").join("abcd"), &[("a.aqp", b"NIFL a")]);
+        make_ice(&tmp.0, &datadir.join(".modpatcher-replaced").join("win32").join("aaa"), &[("a.aqp", b"NIFL a")]);
+
+        let (index, _) = EntryIndex::build(&datadir, &datadir.join("win32").join("..").join("old-backups"), None, false).unwrap();
+        let keys: Vec<&str> = index.find("a.aqp").unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["win32/aaa"]);
+    }
+
+    #[test]
+    fn make_patch_skips_the_same_directories_as_indexing() {
+        let tmp = TempDir::new("make-patch-skip");
+        let original = tmp.0.join("original");
+        let modified = tmp.0.join("modified");
+        for (dir, contents) in [(&original, b"NIFL old"), (&modified, b"NIFL new")].iter() {
+            make_ice(&tmp.0, &dir.join("win32").join("aaa"), &[("a.aqp", &contents[..])]);
+            make_ice(&tmp.0, &dir.join("old-backups").join("blobs").join("ab").join("abcd"), &[("a.aqp", &contents[..])]);
+            make_ice(&tmp.0, &dir.join(".modpatcher-replaced").join("win32").join("aaa"), &[("a.aqp", &contents[..])]);
+        }
+
+        let out = tmp.0.join("out");
+        let originals = make_patch::Originals::Datadir(&original);
+        let count = make_patch::make_patch(&originals, &modified, &modified.join("old-backups"), &out, false).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(std::fs::read(out.join("win32").join("aaa_ice").join("1").join("a.aqp")).unwrap(), b"NIFL new");
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn verify_follows_renames_of_swapped_entries() {
+        let tmp = TempDir::new("verify-swap-rename");
+        let datadir = tmp.0.join("data");
+        let source_path = datadir.join("win32").join("source");
+        let target_path = datadir.join("win32").join("target");
+        make_ice(&tmp.0, &source_path, &[("pl_123.aqp", b"NIFL swapped")]);
+        make_ice(&tmp.0, &target_path, &[("b.aqp", b"NIFL target")]);
+
+        // the entry swapped in by the first is renamed by the second
+        let swap_src = tmp.0.join("swap").join("win32").join("target_ice");
+        write(&swap_src.join(swap::SWAP_FILE_NAME), b"source = \"win32/source\"\n");
+        let rename_src = tmp.0.join("rename").join("win32").join("target_ice");
+        write(&rename_src.join(rename::RENAME_FILE_NAME), b"[[rule]]\nfrom = \"_123.\"\nto = \"_456.\"\n");
+
+        let backup_dir = tmp.0.join("backup");
+        let mut ctx = context(&datadir, ValidationLevel::Off, &backup_dir);
+        apply_directory(&swap_src, &target_path, &mut ctx).unwrap();
+        apply_directory(&rename_src, &target_path, &mut ctx).unwrap();
+        let target = ice::read_ice(&target_path).unwrap();
+        assert!(target.group(Group::Group1).iter().any(|e| e.name == "pl_456.aqp"));
+
+        assert_eq!(verify::verify(&datadir, &ctx.state, false).unwrap(), 0);
+    }
+
+    /// Every file under `dir` and its contents, other than the staging
+    /// directory of profile switches.
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        fn walk(root: &Path, dir: &Path, files: &mut BTreeMap<PathBuf, Vec<u8>>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.ends_with(".modpatcher-switch") {
+                    continue;
+                }
+                if path.is_dir() {
+                    walk(root, &path, files);
+                } else {
+                    files.insert(path.strip_prefix(root).unwrap().to_path_buf(), std::fs::read(&path).unwrap());
+                }
+            }
+        }
+        let mut files = BTreeMap::new();
+        walk(dir, dir, &mut files);
+        files
+    }
+
+    /// A data directory with two ICE files, and a patch for each of them.
+    fn two_patches(tmp: &Path) -> (PathBuf, PathBuf, PathBuf) {
+        let datadir = tmp.join("data");
+        make_ice(tmp, &datadir.join("win32").join("aaa"), &[("a.aqp", b"NIFL a")]);
+        make_ice(tmp, &datadir.join("win32_na").join("bbb"), &[("b.aqp", b"NIFL b")]);
+        let one = tmp.join("one");
+        write(&one.join("win32").join("aaa_ice").join("1").join("a.aqp"), b"NIFL one");
+        let two = tmp.join("two");
+        write(&two.join("win32_na").join("bbb_ice").join("1").join("b.aqp"), b"NIFL two");
+        (datadir, one, two)
+    }
+
+    fn entry(ice_path: &Path, name: &str) -> Vec<u8> {
+        let contents = ice::read_ice(ice_path).unwrap();
+        contents.group(Group::Group1).iter().find(|e| e.name == name).unwrap().data.clone()
+    }
+
+    #[test]
+    fn profiles_switch_both_ways() {
+        let tmp = TempDir::new("switch");
+        let (datadir, one, two) = two_patches(&tmp.0);
+        let aaa = datadir.join("win32").join("aaa");
+        let bbb = datadir.join("win32_na").join("bbb");
+        let originals = snapshot(&datadir);
+        let original = |path: &Path| originals[path.strip_prefix(&datadir).unwrap()].clone();
+        let keys = |ctx: &PatchContext| ctx.state.ices.keys().cloned().collect::<Vec<_>>();
+
+        let mut ctx = context(&datadir, ValidationLevel::Off, &tmp.0.join("backup"));
+        switch_patches(&mut ctx, std::slice::from_ref(&one)).unwrap();
+        assert_eq!(entry(&aaa, "a.aqp"), b"NIFL one");
+        assert_eq!(std::fs::read(&bbb).unwrap(), original(&bbb));
+        assert_eq!(keys(&ctx), ["win32/aaa"]);
+
+        switch_patches(&mut ctx, &[two]).unwrap();
+        assert_eq!(std::fs::read(&aaa).unwrap(), original(&aaa));
+        assert_eq!(entry(&bbb, "b.aqp"), b"NIFL two");
+        assert_eq!(keys(&ctx), ["win32_na/bbb"]);
+
+        switch_patches(&mut ctx, &[one]).unwrap();
+        assert_eq!(entry(&aaa, "a.aqp"), b"NIFL one");
+        assert_eq!(std::fs::read(&bbb).unwrap(), original(&bbb));
+        assert_eq!(keys(&ctx), ["win32/aaa"]);
+
+        switch_patches(&mut ctx, &[]).unwrap();
+        assert_eq!(snapshot(&datadir), originals);
+        assert!(ctx.state.ices.is_empty());
+    }
+
+    #[test]
+    fn a_switch_failing_partway_leaves_everything_unchanged() {
+        let tmp = TempDir::new("switch-fail");
+        let (datadir, one, two) = two_patches(&tmp.0);
+        let mut ctx = context(&datadir, ValidationLevel::Off, &tmp.0.join("backup"));
+        switch_patches(&mut ctx, &[one]).unwrap();
+        ctx.state.save(&datadir).unwrap();
+        let before = snapshot(&datadir);
+
+        // as a switch to two would, but with a file where the directory for
+        // moving win32_na/bbb aside should go, so that it fails once
+        // win32/aaa has already been moved
+        let changed = vec!["win32/aaa".to_owned(), "win32_na/bbb".to_owned()];
+        let mut wanted = BTreeMap::new();
+        let source = std::fs::canonicalize(two.join("win32_na").join("bbb_ice")).unwrap();
+        wanted.insert("win32_na/bbb".to_owned(), vec![source]);
+        write(&datadir.join(REPLACED_DIR_NAME).join("win32_na"), b"");
+        let e = rebuild_changed(&mut ctx, &changed, &wanted).unwrap_err();
+        assert!(format!("{:#}", e).contains("the data directory is unchanged"), "{:#}", e);
+
+        ctx.state.save(&datadir).unwrap();
+        assert_eq!(snapshot(&datadir), before);
+        assert_eq!(entry(&datadir.join("win32").join("aaa"), "a.aqp"), b"NIFL one");
+    }
+
+    #[test]
+    fn failed_moves_are_undone() {
+        let tmp = TempDir::new("undo");
+        let datadir = tmp.0.join("data");
+        let staging = datadir.join(".modpatcher-switch");
+        let replaced = datadir.join(REPLACED_DIR_NAME);
+        let keys = vec!["a/one".to_owned(), "b/two".to_owned()];
+        write(&datadir.join("a/one"), b"old one");
+        write(&datadir.join("b/two"), b"old two");
+        write(&staging.join("a/one"), b"new one");
+        write(&staging.join("b/two"), b"new two");
+        // a file where the directory for moving b/two aside should go
+        write(&replaced.join("b"), b"");
+
+        let mut moves = Vec::new();
+        move_staged(&datadir, &staging, &replaced, &keys, &mut moves).unwrap_err();
+        assert_eq!(std::fs::read(datadir.join("a/one")).unwrap(), b"new one");
+        undo_moves(&datadir, &staging, &replaced, &moves).unwrap();
+        assert_eq!(std::fs::read(datadir.join("a/one")).unwrap(), b"old one");
+        assert_eq!(std::fs::read(datadir.join("b/two")).unwrap(), b"old two");
+        assert_eq!(std::fs::read(staging.join("a/one")).unwrap(), b"new one");
+    }
+
+    #[test]
+    fn help_describes_the_tool() {
+        let mut help = Vec::new();
+        Args::clap().write_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        assert!(help.contains("Tool for repacking ICE archives"), "{}", help);
+        assert!(!help.contains("Options for ICE files"), "{}", help);
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, structopt::clap::Error> {
+        Args::from_iter_safe(std::iter::once("pso2-modpatcher").chain(args.iter().copied()))
+    }
+
+    /// Run the command line `args` as `main` would.
+    fn cli(args: &[&str]) -> anyhow::Result<()> {
+        run(&parse(args).unwrap(), mpsc::channel().0)
+    }
+
+    fn arg(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    #[test]
+    fn diff_json_lists_every_difference() {
+        let tmp = TempDir::new("diff-json");
+        let a = tmp.0.join("a.ice");
+        make_ice(&tmp.0, &a, &[("kept.aqp", b"NIFL kept"), ("changed.aqp", b"NIFL old"), ("removed.aqp", b"NIFL gone")]);
+        let src = tmp.0.join("b");
+        write(&src.join("1").join("kept.aqp"), b"NIFL kept");
+        write(&src.join("1").join("changed.aqp"), b"NIFL new!");
+        write(&src.join("2").join("added.aqp"), b"NIFL added");
+        let b = tmp.0.join("b.ice");
+        cli(&["pack", arg(&src), arg(&b), "--compress"]).unwrap();
+
+        let diff = diff::diff_ice(&ice::read_ice(&a).unwrap(), &ice::read_ice(&b).unwrap());
+        let json = serde_json::to_value(&diff).unwrap();
+        let info = |name: &str, contents: &[u8]| serde_json::json!({
+            "name": name,
+            "ext": "aqp",
+            "size": contents.len(),
+            "hash": hash::hash_bytes(contents),
+        });
+        assert_eq!(json, serde_json::json!({
+            "header": [
+                { "field": "group 1 compressed", "a": "false", "b": "true" },
+                { "field": "group 2 compressed", "a": "false", "b": "true" },
+            ],
+            "groups": [
+                {
+                    "group": 1,
+                    "added": [],
+                    "removed": [info("removed.aqp", b"NIFL gone")],
+                    "changed": [{
+                        "name": "changed.aqp",
+                        "a": info("changed.aqp", b"NIFL old"),
+                        "b": info("changed.aqp", b"NIFL new!"),
+                    }],
+                },
+                {
+                    "group": 2,
+                    "added": [info("added.aqp", b"NIFL added")],
+                    "removed": [],
+                    "changed": [],
+                },
+            ],
+        }));
+        cli(&["diff", arg(&a), arg(&b), "--json"]).unwrap();
+    }
+
+    #[test]
+    fn make_patch_reads_originals_from_backups() {
+        let tmp = TempDir::new("make-patch-backup");
+        let (datadir, one, two) = two_patches(&tmp.0);
+        cli(&["apply", arg(&one), arg(&datadir)]).unwrap();
+        cli(&["apply", arg(&two), arg(&datadir)]).unwrap();
+
+        let backup = datadir.join("backup");
+        let out = tmp.0.join("out");
+        cli(&["make-patch", arg(&backup), arg(&datadir), arg(&out)]).unwrap();
+        assert_eq!(snapshot(&out), snapshot(&one).into_iter().chain(snapshot(&two)).collect());
+
+        let e = cli(&["make-patch", arg(&backup), arg(&datadir), arg(&tmp.0.join("out2")), "--generation", "19700101-000000"]).unwrap_err();
+        assert!(format!("{:#}", e).contains("has no backup generation 19700101-000000"), "{:#}", e);
+    }
+
+    #[test]
+    fn lint_lists_what_patching_would_ignore() {
+        let tmp = TempDir::new("lint");
+        let (datadir, one, _) = two_patches(&tmp.0);
+        let aaa_ice = one.join("win32").join("aaa_ice");
+        write(&one.join("readme.txt"), b"read me");
+        write(&aaa_ice.join("notes.txt"), b"notes");
+        write(&aaa_ice.join("extra").join("b.aqp"), b"NIFL b");
+        std::fs::create_dir_all(one.join("win32").join("empty_ice")).unwrap();
+        write(&one.join("win32").join("swapped_ice").join(swap::SWAP_FILE_NAME), b"source = 1\n");
+
+        let findings = lint::lint(&one).unwrap();
+        let findings: Vec<&str> = findings.iter().map(|f| f.strip_prefix(arg(&one)).unwrap_or(f)).collect();
+        assert_eq!(findings.len(), 5, "{:?}", findings);
+        assert_eq!(findings[0], "/readme.txt: ignored; files must be inside the 1 or 2 directory of an _ice directory");
+        assert_eq!(findings[1], "/win32/aaa_ice/extra: ignored; an _ice directory may only contain 1 and 2 directories");
+        assert_eq!(findings[2], "/win32/aaa_ice/notes.txt: ignored; files must be inside the 1 or 2 directory");
+        assert_eq!(findings[3], "/win32/empty_ice: has no 1 or 2 directory, swap or rename rules to patch from");
+        assert!(findings[4].contains("swap.toml"), "{}", findings[4]);
+        let e = cli(&["lint", arg(&one)]).unwrap_err();
+        assert_eq!(format!("{:#}", e), "5 problems found");
+
+        // the problems are only warnings when patching, so the rest applies
+        cli(&["apply", arg(&one), arg(&datadir)]).unwrap();
+        assert_eq!(entry(&datadir.join("win32").join("aaa"), "a.aqp"), b"NIFL one");
+    }
+
+    #[test]
+    fn cached_outputs_are_used_unless_corrupt() {
+        let tmp = TempDir::new("cache");
+        let (datadir, one, _) = two_patches(&tmp.0);
+        let cache = tmp.0.join("cache");
+        let copies: Vec<PathBuf> = (0..2).map(|i| {
+            let copy = tmp.0.join(format!("data{}", i));
+            make_ice(&tmp.0, &copy.join("win32").join("aaa"), &[("a.aqp", b"NIFL a")]);
+            copy
+        }).collect();
+
+        // a dry run leaves no cache behind
+        cli(&["--cache-dir", arg(&cache), "apply", arg(&one), arg(&datadir), "--dry-run"]).unwrap();
+        assert!(!cache.exists());
+
+        cli(&["--cache-dir", arg(&cache), "apply", arg(&one), arg(&datadir)]).unwrap();
+        let patched = std::fs::read(datadir.join("win32").join("aaa")).unwrap();
+        let cached: Vec<PathBuf> = std::fs::read_dir(&cache).unwrap().map(|e| e.unwrap().path()).collect();
+        let cached_ice = cached.iter().find(|p| p.extension().is_some_and(|e| e == "ice")).unwrap();
+        let cached_json = cached_ice.with_extension("json");
+        assert_eq!(std::fs::read(cached_ice).unwrap(), patched);
+
+        // swap in another output, recorded as it would have been: patching
+        // the same original with the same files copies it
+        make_ice(&tmp.0, cached_ice, &[("a.aqp", b"NIFL from the cache")]);
+        let mut cache_entry: CacheEntry = serde_json::from_slice(&std::fs::read(&cached_json).unwrap()).unwrap();
+        cache_entry.output_hash = hash::hash_file(cached_ice).unwrap();
+        std::fs::write(&cached_json, serde_json::to_vec(&cache_entry).unwrap()).unwrap();
+        cli(&["--cache-dir", arg(&cache), "apply", arg(&one), arg(&copies[0])]).unwrap();
+        assert_eq!(entry(&copies[0].join("win32").join("aaa"), "a.aqp"), b"NIFL from the cache");
+
+        // but not once it no longer matches its recorded hash
+        std::fs::write(cached_ice, b"corrupt").unwrap();
+        cli(&["--cache-dir", arg(&cache), "apply", arg(&one), arg(&copies[1])]).unwrap();
+        assert_eq!(std::fs::read(copies[1].join("win32").join("aaa")).unwrap(), patched);
+    }
+
+    #[test]
+    fn status_lists_patched_files_and_their_condition() {
+        let tmp = TempDir::new("status");
+        let (datadir, one, two) = two_patches(&tmp.0);
+        let status = || {
+            let mut out = Vec::new();
+            status::status(&datadir, &InstallState::load(&datadir).unwrap(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(status(), format!("No ICE files have been patched in {}\n", arg(&datadir)));
+
+        cli(&["apply", arg(&one), arg(&datadir), "--include", "win32/aaa/1/*"]).unwrap();
+        cli(&["apply", arg(&two), arg(&datadir), "--no-backup"]).unwrap();
+        // the game replaces one of them
+        make_ice(&tmp.0, &datadir.join("win32_na").join("bbb"), &[("b.aqp", b"NIFL updated")]);
+
+        let state = InstallState::load(&datadir).unwrap();
+        let (aaa, bbb) = (&state.ices["win32/aaa"], &state.ices["win32_na/bbb"]);
+        cli(&["status", arg(&datadir)]).unwrap();
+        assert_eq!(status(), format!(
+            "win32/aaa: modified, as patched\n  patched at {}\n  from {} (filtered)\n  backup {}\n\
+             win32_na/bbb: modified, replaced since it was patched; run reapply\n  patched at {}\n  from {}\n  no backup\n\
+             \n\
+             2 ICE files patched from 2 patch sources\n\
+             1 of them no longer match what was written\n",
+            state::format_time(aaa.patched_at),
+            aaa.sources[0].to_string_lossy(),
+            aaa.backup.as_ref().unwrap().to_string_lossy(),
+            state::format_time(bbb.patched_at),
+            bbb.sources[0].to_string_lossy(),
+        ));
+    }
+
+    #[test]
+    fn index_rereads_only_changed_files() {
+        let tmp = TempDir::new("index");
+        let (datadir, _, _) = two_patches(&tmp.0);
+        write(&datadir.join("readme.txt"), b"not an ICE file");
+        let e = cli(&["find", arg(&datadir), "*.aqp"]).unwrap_err();
+        assert!(format!("{:#}", e).contains("has not been indexed"), "{:#}", e);
+
+        cli(&["index", arg(&datadir)]).unwrap();
+        cli(&["find", arg(&datadir), "*.aqp"]).unwrap();
+        let mut index = EntryIndex::load(&datadir).unwrap().unwrap();
+        let found: Vec<(&str, &str)> = index.find("*.AQP").unwrap().into_iter().map(|(k, e)| (k, e.name.as_str())).collect();
+        assert_eq!(found, [("win32/aaa", "a.aqp"), ("win32_na/bbb", "b.aqp")]);
+        let a = &index.ices["win32/aaa"].entries[0];
+        assert_eq!((a.group, a.size, a.hash.as_str()), (1, 6, hash::hash_bytes(b"NIFL a").as_str()));
+
+        // entries of files unchanged since are taken from the index rather
+        // than read again, as the entry renamed here shows
+        index.ices.get_mut("win32_na/bbb").unwrap().entries[0].name = "indexed.aqp".to_owned();
+        make_ice(&tmp.0, &datadir.join("win32").join("aaa"), &[("a.aqp", b"NIFL a"), ("c.aqp", b"NIFL c")]);
+        let (index, stats) = EntryIndex::build(&datadir, &datadir.join("backup"), Some(index), false).unwrap();
+        assert_eq!((stats.read, stats.reused, stats.failed), (1, 1, 0));
+        let found: Vec<(&str, &str)> = index.find("*.aqp").unwrap().into_iter().map(|(k, e)| (k, e.name.as_str())).collect();
+        assert_eq!(found, [("win32/aaa", "a.aqp"), ("win32/aaa", "c.aqp"), ("win32_na/bbb", "indexed.aqp")]);
+    }
+
+    #[test]
+    fn everywhere_patches_every_holder_and_removes_stale_directories() {
+        let tmp = TempDir::new("everywhere");
+        let datadir = tmp.0.join("data");
+        let (aaa, bbb, ccc) = (datadir.join("win32").join("aaa"), datadir.join("win32_na").join("bbb"), datadir.join("win32").join("ccc"));
+        make_ice(&tmp.0, &aaa, &[("a.aqp", b"NIFL a"), ("ui.dds", b"old ui")]);
+        make_ice(&tmp.0, &bbb, &[("UI.dds", b"old ui")]);
+        make_ice(&tmp.0, &ccc, &[("c.aqp", b"NIFL c")]);
+        let untouched = std::fs::read(&ccc).unwrap();
+        let package = tmp.0.join("mod");
+        write(&package.join(manifest::MANIFEST_FILE_NAME), b"[everywhere]\n\"ui.dds\" = \"shared/ui.dds\"\n");
+        write(&package.join("shared").join("ui.dds"), b"new ui");
+
+        // a dry run writes nothing to the data directory
+        let before = snapshot(&datadir);
+        cli(&["--validate", "off", "apply", arg(&package), arg(&datadir), "--dry-run"]).unwrap();
+        assert_eq!(snapshot(&datadir), before);
+        assert!(!datadir.join(".modpatcher-everywhere").exists());
+
+        cli(&["--validate", "off", "apply", arg(&package), arg(&datadir)]).unwrap();
+        assert_eq!(entry(&aaa, "ui.dds"), b"new ui");
+        assert_eq!(entry(&aaa, "a.aqp"), b"NIFL a");
+        assert_eq!(entry(&bbb, "UI.dds"), b"new ui");
+        assert_eq!(std::fs::read(&ccc).unwrap(), untouched);
+        let expanded: Vec<PathBuf> = std::fs::read_dir(datadir.join(".modpatcher-everywhere")).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(expanded.len(), 1);
+        let expanded = &expanded[0];
+        assert!(expanded.join("win32").join("aaa_ice").join("1").join("ui.dds").is_file());
+        assert!(expanded.join("win32_na").join("bbb_ice").join("1").join("UI.dds").is_file());
+        cli(&["verify", arg(&datadir)]).unwrap();
+
+        // left from an earlier run, for an ICE file that no longer holds the
+        // entry and was never patched from it
+        write(&expanded.join("win32").join("zzz_ice").join("1").join("ui.dds"), b"new ui");
+        write(&expanded.join("win32reboot").join("ab").join("cdef_ice").join("1").join("ui.dds"), b"new ui");
+        cli(&["--validate", "off", "apply", arg(&package), arg(&datadir)]).unwrap();
+        assert!(!expanded.join("win32").join("zzz_ice").exists());
+        assert!(!expanded.join("win32reboot").exists());
+        assert!(expanded.join("win32").join("aaa_ice").is_dir());
+        assert!(expanded.join("win32_na").join("bbb_ice").is_dir());
+        cli(&["verify", arg(&datadir)]).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_drift() {
+        let tmp = TempDir::new("verify-drift");
+        let (datadir, one, two) = two_patches(&tmp.0);
+        cli(&["apply", arg(&one), arg(&datadir)]).unwrap();
+        cli(&["apply", arg(&two), arg(&datadir)]).unwrap();
+        cli(&["verify", arg(&datadir)]).unwrap();
+
+        // a patch file edited since it was applied
+        let patch_file = one.join("win32").join("aaa_ice").join("1").join("a.aqp");
+        write(&patch_file, b"NIFL edited");
+        let e = cli(&["verify", arg(&datadir)]).unwrap_err();
+        assert_eq!(format!("{:#}", e), "1 of 2 patched ICE files have drifted");
+        write(&patch_file, b"NIFL one");
+
+        // a patched file removed from the data directory
+        std::fs::remove_file(datadir.join("win32_na").join("bbb")).unwrap();
+        let state = InstallState::load(&datadir).unwrap();
+        assert_eq!(verify::verify(&datadir, &state, false).unwrap(), 1);
+        let e = cli(&["verify", arg(&datadir)]).unwrap_err();
+        assert_eq!(format!("{:#}", e), "1 of 2 patched ICE files have drifted");
+    }
+
+    #[test]
+    fn reapply_patches_files_the_game_replaced() {
+        let tmp = TempDir::new("reapply");
+        let (datadir, one, _) = two_patches(&tmp.0);
+        let aaa = datadir.join("win32").join("aaa");
+        cli(&["apply", arg(&one), arg(&datadir)]).unwrap();
+        assert_eq!(entry(&aaa, "a.aqp"), b"NIFL one");
+
+        // an update replaces the patched file with a new original
+        make_ice(&tmp.0, &aaa, &[("a.aqp", b"NIFL updated"), ("c.aqp", b"NIFL new in the update")]);
+        let updated = std::fs::read(&aaa).unwrap();
+        cli(&["reapply", arg(&datadir)]).unwrap();
+
+        assert_eq!(entry(&aaa, "a.aqp"), b"NIFL one");
+        assert_eq!(entry(&aaa, "c.aqp"), b"NIFL new in the update");
+        let state = InstallState::load(&datadir).unwrap();
+        let record = &state.ices["win32/aaa"];
+        assert_eq!(record.patched_hash, hash::hash_file(&aaa).unwrap());
+        assert_eq!(std::fs::read(record.backup.as_ref().unwrap()).unwrap(), updated);
+
+        // and the file is then left alone
+        let reapplied = std::fs::read(&aaa).unwrap();
+        cli(&["reapply", arg(&datadir)]).unwrap();
+        assert_eq!(std::fs::read(&aaa).unwrap(), reapplied);
+    }
+
+    #[test]
+    fn apply_takes_any_patch_directory_name() {
+        for name in ["lint", "diff", "status", "verify", "pack", "index", "patch", "mod"].iter() {
+            match parse(&["apply", name, "datadir"]).unwrap().command {
+                Command::Apply(apply) => {
+                    assert_eq!(apply.input, Path::new(name));
+                    assert_eq!(apply.datadir, Path::new("datadir"));
+                },
+                command => panic!("{} parsed as {:?}", name, command),
+            }
+        }
+    }
+
+    #[test]
+    fn apply_options_follow_the_command() {
+        let args = parse(&["-v", "apply", "--dry-run", "--include", "win32/*", "mod", "datadir"]).unwrap();
+        assert!(args.verbose);
+        match args.command {
+            Command::Apply(apply) => {
+                assert!(apply.dry_run);
+                assert_eq!(apply.include, ["win32/*"]);
+            },
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn patching_needs_the_apply_command() {
+        assert!(parse(&["mod", "datadir"]).is_err());
+        assert!(parse(&["patch", "datadir"]).is_err());
+        assert!(parse(&[]).is_err());
+    }
+
+    #[test]
+    fn subcommands_still_parse_after_global_options() {
+        match parse(&["-v", "status", "datadir"]).unwrap().command {
+            Command::Status { datadir } => assert_eq!(datadir, Path::new("datadir")),
+            command => panic!("parsed as {:?}", command),
+        }
+        match parse(&["lint", "mod"]).unwrap().command {
+            Command::Lint { input } => assert_eq!(input, Path::new("mod")),
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn ice_format_options_are_read_wherever_they_are_given() {
+        for args in [
+            &["--ice-version", "3", "--encrypt", "pack", "dir", "out.ice"][..],
+            &["pack", "--ice-version", "3", "--encrypt", "dir", "out.ice"][..],
+        ].iter() {
+            let args = parse(args).unwrap();
+            assert!(matches!(args.command, Command::Pack { .. }));
+            assert_eq!(args.new_ice.version, 3);
+            assert!(args.new_ice.encrypt);
+        }
+
+        let args = parse(&["apply", "--create-missing", "--compress", "mod", "datadir"]).unwrap();
+        assert!(args.create_missing);
+        assert!(args.new_ice.compress);
     }
 }
@@ -0,0 +1,135 @@
+//! Generating a patch directory from the differences between data directories.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::backup::BackupStore;
+use crate::diff;
+use crate::hash;
+use crate::ice;
+use crate::index;
+use crate::state;
+
+/// Where the originals of the ICE files of a modified data directory are.
+pub enum Originals<'a> {
+    /// A data directory with the same layout.
+    Datadir(&'a Path),
+    /// A backup directory, holding the originals backed up in a generation,
+    /// or in the newest generation that backed up each file if `None`.
+    Backup(&'a BackupStore, Option<&'a str>),
+}
+
+impl Originals<'_> {
+    /// The original of the ICE file at `rel` in the modified data directory.
+    fn find(&self, rel: &Path) -> Option<PathBuf> {
+        match self {
+            Originals::Datadir(dir) => Some(dir.join(rel)).filter(|p| p.is_file()),
+            Originals::Backup(backups, generation) => backups.original(&state::key(Path::new(""), rel), *generation),
+        }
+    }
+}
+
+/// Write a patch directory to `out` containing every entry added or changed in
+/// the ICE files of `modified` relative to their originals, skipping its backup
+/// directory `backup_dir`. Returns the number of ICE files with changes.
+pub fn make_patch(original: &Originals, modified: &Path, backup_dir: &Path, out: &Path, verbose: bool) -> anyhow::Result<usize> {
+    let mut count = 0;
+    walk(original, modified, backup_dir, out, Path::new(""), verbose, &mut count)?;
+    Ok(count)
+}
+
+fn walk(
+    original: &Originals,
+    modified: &Path,
+    backup_dir: &Path,
+    out: &Path,
+    rel: &Path,
+    verbose: bool,
+    count: &mut usize,
+) -> anyhow::Result<()> {
+    let dir = modified.join(rel);
+    let read_dir = dir.read_dir()
+        .with_context(|| format!("Failed to iterate over directory {}", dir.to_string_lossy()))?;
+    for file in read_dir {
+        let file = file
+            .with_context(|| format!("Failed to index a file in directory {}", dir.to_string_lossy()))?;
+        let file_rel = rel.join(file.file_name());
+        let path = file.path();
+
+        // don't diff our own backups and files when given a patched data
+        // directory
+        if index::is_own_path(&path, backup_dir) {
+            continue;
+        }
+        if path.is_dir() {
+            walk(original, modified, backup_dir, out, &file_rel, verbose, count)?;
+        } else if ice::is_ice(&path)? {
+            let orig_path = match original.find(&file_rel) {
+                Some(p) => p,
+                None => {
+                    eprintln!("{} has no original; skipping", file_rel.to_string_lossy());
+                    continue;
+                },
+            };
+            if diff_ice_file(&orig_path, &path, out, &file_rel, verbose)
+                .with_context(|| format!("Failed to make a patch for {}", file_rel.to_string_lossy()))? {
+                *count += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn diff_ice_file(orig_path: &Path, mod_path: &Path, out: &Path, rel: &Path, verbose: bool) -> anyhow::Result<bool> {
+    if hash::hash_file(orig_path)? == hash::hash_file(mod_path)? {
+        return Ok(false);
+    }
+
+    let orig = ice::read_ice(orig_path)?;
+    let modded = ice::read_ice(mod_path)?;
+    let diff = diff::diff_ice(&orig, &modded);
+
+    let mut ice_dir_name = rel.file_name().unwrap().to_os_string();
+    ice_dir_name.push("_ice");
+    let ice_dir = out.join(rel).with_file_name(ice_dir_name);
+
+    for change in diff.header.iter() {
+        eprintln!(
+            "{}: {} changed from {} to {}, which a patch can't express",
+            rel.to_string_lossy(), change.field, change.a, change.b,
+        );
+    }
+
+    let mut written = false;
+    for (group_diff, &group) in diff.groups.iter().zip(ice::GROUPS.iter()) {
+        for removed in group_diff.removed.iter() {
+            eprintln!(
+                "{}: {} entry {} was removed, which a patch can't express",
+                rel.to_string_lossy(), group, removed.name,
+            );
+        }
+
+        let names = group_diff.changed.iter().map(|c| &c.name)
+            .chain(group_diff.added.iter().map(|a| &a.name));
+        for name in names {
+            if name.contains(['/', '\\']) || name == "." || name == ".." {
+                bail!("{} entry name {:?} can't be written as a file", group, name);
+            }
+            let entry = modded.group(group).iter().find(|e| &e.name == name).unwrap();
+
+            let group_dir = ice_dir.join((ice::group_index(group) + 1).to_string());
+            std::fs::create_dir_all(&group_dir)
+                .with_context(|| format!("Failed to make patch directory {}", group_dir.to_string_lossy()))?;
+            let entry_path = group_dir.join(name);
+            if verbose {
+                eprintln!("Writing {}", entry_path.to_string_lossy());
+            }
+            std::fs::write(&entry_path, &entry.data)
+                .with_context(|| format!("Failed to write patch file {}", entry_path.to_string_lossy()))?;
+            written = true;
+        }
+    }
+
+    Ok(written)
+}
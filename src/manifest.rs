@@ -0,0 +1,341 @@
+//! Manifests declaring the optional parts of a mod package.
+//!
+//! A patch directory may contain a `modpatcher.toml` naming subdirectories
+//! that are only applied when selected. Each subdirectory is laid out like a
+//! patch directory of its own, with its own `win32` and `win32reboot`
+//! directories. Everything else in the patch directory is always applied.
+//!
+//! ```toml
+//! [components.hat]
+//! path = "optional/hat"
+//! default = true
+//!
+//! [variants.colour]
+//! default = "red"
+//! choices = { red = "colour/red", blue = "colour/blue" }
+//! ```
+//!
+//! A component is installed or not; exactly one choice of each variant group
+//! is installed.
+//!
+//! The `everywhere` section names files that replace every entry of the same
+//! name, in whichever ICE files of the data directory hold one:
+//!
+//! ```toml
+//! [everywhere]
+//! "ui_common.dds" = "shared/ui_common.dds"
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component as PathComponent, Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::ice;
+
+/// File name of the manifest within a patch directory.
+pub const MANIFEST_FILE_NAME: &str = "modpatcher.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub components: BTreeMap<String, Component>,
+    #[serde(default)]
+    pub variants: BTreeMap<String, VariantGroup>,
+    /// Files replacing every entry of the same name, keyed by entry name.
+    #[serde(default)]
+    pub everywhere: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Component {
+    pub path: PathBuf,
+    /// Whether the component is installed unless deselected.
+    #[serde(default)]
+    pub default: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantGroup {
+    /// Choice installed unless another is selected. Without one, a choice must
+    /// always be given.
+    pub default: Option<String>,
+    pub choices: BTreeMap<String, PathBuf>,
+}
+
+/// The components and variants chosen from a manifest, as recorded in the
+/// install state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    #[serde(default)]
+    pub components: BTreeSet<String>,
+    /// Chosen variant of each variant group.
+    #[serde(default)]
+    pub variants: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Load the manifest of a patch directory, if it has one.
+    pub fn load(patch_dir: &Path) -> anyhow::Result<Option<Manifest>> {
+        let path = patch_dir.join(MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read manifest {}", path.to_string_lossy()))?;
+        let manifest: Manifest = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse manifest {}", path.to_string_lossy()))?;
+        manifest.check(patch_dir)
+            .with_context(|| format!("Invalid manifest {}", path.to_string_lossy()))?;
+        Ok(Some(manifest))
+    }
+
+    fn check(&self, patch_dir: &Path) -> anyhow::Result<()> {
+        for (name, group) in self.variants.iter() {
+            if group.choices.is_empty() {
+                bail!("Variant group {} has no choices", name);
+            }
+            if let Some(default) = &group.default {
+                if !group.choices.contains_key(default) {
+                    bail!("Default {} of variant group {} is not one of its choices", default, name);
+                }
+            }
+        }
+        for path in self.paths() {
+            check_inside(path)?;
+            if !patch_dir.join(path).is_dir() {
+                bail!("{} is not a directory", patch_dir.join(path).to_string_lossy());
+            }
+        }
+        for (name, path) in self.everywhere.iter() {
+            ice::check_entry_name(name).map_err(|e| anyhow::anyhow!("{} can't be an entry name: {}", name, e))?;
+            check_inside(path)?;
+            if !patch_dir.join(path).is_file() {
+                bail!("{} is not a file", patch_dir.join(path).to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+
+    /// Every subdirectory the manifest declares, selected or not.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        let components = self.components.values().map(|c| c.path.as_path());
+        let variants = self.variants.values().flat_map(|g| g.choices.values().map(|p| p.as_path()));
+        components.chain(variants)
+    }
+
+    /// The subdirectories to apply for `selection`: components, then the
+    /// chosen variants, each in name order.
+    pub fn selected_paths<'a>(&'a self, selection: &'a Selection) -> impl Iterator<Item = &'a Path> {
+        let components = self.components
+            .iter()
+            .filter(move |(name, _)| selection.components.contains(*name))
+            .map(|(_, c)| c.path.as_path());
+        let variants = self.variants
+            .iter()
+            .filter_map(move |(name, g)| selection.variants.get(name).and_then(|choice| g.choices.get(choice)))
+            .map(|p| p.as_path());
+        components.chain(variants)
+    }
+
+    /// Resolve what to install, starting from `previous` if the package was
+    /// installed before or the defaults otherwise, then applying the
+    /// components added with `with`, removed with `without`, and the
+    /// `(group, choice)` pairs of `variants`.
+    pub fn select(
+        &self,
+        previous: Option<&Selection>,
+        with: &[String],
+        without: &[String],
+        variants: &[(String, String)],
+    ) -> anyhow::Result<Selection> {
+        let mut selection = match previous {
+            Some(previous) => previous.clone(),
+            None => Selection {
+                components: self.components.iter().filter(|(_, c)| c.default).map(|(n, _)| n.clone()).collect(),
+                variants: self.variants
+                    .iter()
+                    .filter_map(|(n, g)| g.default.clone().map(|d| (n.clone(), d)))
+                    .collect(),
+            },
+        };
+        // anything the manifest no longer declares is dropped
+        selection.components.retain(|n| self.components.contains_key(n));
+        selection.variants.retain(|n, c| self.variants.get(n).is_some_and(|g| g.choices.contains_key(c)));
+
+        for name in with.iter().chain(without.iter()) {
+            if !self.components.contains_key(name) {
+                bail!("No component named {}; the package has {}", name, list(self.components.keys()));
+            }
+        }
+        selection.components.extend(with.iter().cloned());
+        for name in without {
+            selection.components.remove(name);
+        }
+
+        for (name, choice) in variants {
+            let group = match self.variants.get(name) {
+                Some(g) => g,
+                None => bail!("No variant group named {}; the package has {}", name, list(self.variants.keys())),
+            };
+            if !group.choices.contains_key(choice) {
+                bail!("Variant group {} has no choice {}; choose from {}", name, choice, list(group.choices.keys()));
+            }
+            selection.variants.insert(name.clone(), choice.clone());
+        }
+
+        for (name, group) in self.variants.iter() {
+            if !selection.variants.contains_key(name) {
+                bail!(
+                    "Choose a variant of {} with --variant {}=<choice>, from {}",
+                    name,
+                    name,
+                    list(group.choices.keys()),
+                );
+            }
+        }
+        Ok(selection)
+    }
+}
+
+impl Selection {
+    /// Describe the selection in a line, e.g. `components hat; colour red`.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.components.is_empty() {
+            parts.push(format!("components {}", list(self.components.iter())));
+        }
+        for (name, choice) in self.variants.iter() {
+            parts.push(format!("{} {}", name, choice));
+        }
+        if parts.is_empty() {
+            return "no optional parts".to_owned();
+        }
+        parts.join("; ")
+    }
+}
+
+/// Check that a path from a manifest is within the patch directory.
+fn check_inside(path: &Path) -> anyhow::Result<()> {
+    let inside = path.components().all(|c| matches!(c, PathComponent::Normal(_) | PathComponent::CurDir));
+    if !inside || path.as_os_str().is_empty() {
+        bail!("{} is not within the patch directory", path.to_string_lossy());
+    }
+    Ok(())
+}
+
+fn list<'a>(names: impl Iterator<Item = &'a String>) -> String {
+    let names: Vec<&str> = names.map(|n| n.as_str()).collect();
+    if names.is_empty() {
+        "none".to_owned()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// Parse a `--variant` argument of the form `group=choice`.
+pub fn parse_variant(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((group, choice)) if !group.is_empty() && !choice.is_empty() => Ok((group.to_owned(), choice.to_owned())),
+        _ => Err(format!("expected <group>=<choice>, not {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        toml::from_str(r#"
+            [components.hat]
+            path = "hat"
+            default = true
+
+            [components.scarf]
+            path = "scarf"
+
+            [variants.colour]
+            default = "red"
+            choices = { red = "red", blue = "blue" }
+        "#).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_without_a_previous_selection() {
+        let selection = manifest().select(None, &[], &[], &[]).unwrap();
+        assert_eq!(selection.components, vec!["hat".to_owned()].into_iter().collect());
+        assert_eq!(selection.variants.get("colour").map(|c| c.as_str()), Some("red"));
+        assert_eq!(selection.describe(), "components hat; colour red");
+    }
+
+    #[test]
+    fn flags_change_the_selection() {
+        let variants = [("colour".to_owned(), "blue".to_owned())];
+        let selection = manifest().select(None, &names(&["scarf"]), &names(&["hat"]), &variants).unwrap();
+        assert_eq!(selection.components, vec!["scarf".to_owned()].into_iter().collect());
+        assert_eq!(selection.variants.get("colour").map(|c| c.as_str()), Some("blue"));
+    }
+
+    #[test]
+    fn previous_selection_is_the_starting_point() {
+        let manifest = manifest();
+        let previous = manifest.select(None, &[], &names(&["hat"]), &[]).unwrap();
+        let selection = manifest.select(Some(&previous), &[], &[], &[]).unwrap();
+        assert_eq!(selection, previous);
+        assert!(selection.components.is_empty());
+    }
+
+    #[test]
+    fn parts_no_longer_declared_are_dropped() {
+        let mut previous = Selection::default();
+        previous.components.insert("gloves".to_owned());
+        previous.variants.insert("colour".to_owned(), "green".to_owned());
+        let manifest = manifest();
+        assert!(manifest.select(Some(&previous), &[], &[], &[]).is_err());
+
+        let selection = manifest.select(Some(&previous), &[], &[], &[("colour".to_owned(), "red".to_owned())]).unwrap();
+        assert!(selection.components.is_empty());
+        assert_eq!(selection.variants.get("colour").map(|c| c.as_str()), Some("red"));
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        let manifest = manifest();
+        assert!(manifest.select(None, &names(&["gloves"]), &[], &[]).is_err());
+        assert!(manifest.select(None, &[], &names(&["gloves"]), &[]).is_err());
+        assert!(manifest.select(None, &[], &[], &[("size".to_owned(), "big".to_owned())]).is_err());
+        assert!(manifest.select(None, &[], &[], &[("colour".to_owned(), "green".to_owned())]).is_err());
+    }
+
+    #[test]
+    fn selected_paths_are_components_then_variants() {
+        let manifest = manifest();
+        let selection = manifest.select(None, &names(&["scarf"]), &[], &[]).unwrap();
+        let paths: Vec<&Path> = manifest.selected_paths(&selection).collect();
+        assert_eq!(paths, [Path::new("hat"), Path::new("scarf"), Path::new("red")]);
+    }
+
+    #[test]
+    fn variant_arguments_need_both_halves() {
+        assert_eq!(parse_variant("colour=red"), Ok(("colour".to_owned(), "red".to_owned())));
+        assert!(parse_variant("colour").is_err());
+        assert!(parse_variant("=red").is_err());
+        assert!(parse_variant("colour=").is_err());
+    }
+
+    #[test]
+    fn paths_must_stay_inside_the_patch_directory() {
+        assert!(check_inside(Path::new("optional/hat")).is_ok());
+        assert!(check_inside(Path::new("../hat")).is_err());
+        assert!(check_inside(Path::new("/hat")).is_err());
+        assert!(check_inside(Path::new("")).is_err());
+    }
+}
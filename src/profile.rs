@@ -0,0 +1,39 @@
+//! Named sets of patches that a data directory can be switched between.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+/// File name of the profiles within a data directory.
+const PROFILES_FILE_NAME: &str = "modpatcher-profiles.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Profiles {
+    /// Patch directories of each profile, in the order they are applied.
+    pub profiles: BTreeMap<String, Vec<PathBuf>>,
+}
+
+impl Profiles {
+    /// Load the profiles of a data directory, or none if none have been made.
+    pub fn load(datadir: &Path) -> anyhow::Result<Profiles> {
+        let path = datadir.join(PROFILES_FILE_NAME);
+        if !path.exists() {
+            return Ok(Profiles::default());
+        }
+        let contents = std::fs::read(&path)
+            .with_context(|| format!("Failed to read profiles {}", path.to_string_lossy()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse profiles {}", path.to_string_lossy()))
+    }
+
+    pub fn save(&self, datadir: &Path) -> anyhow::Result<()> {
+        let contents = serde_json::to_vec_pretty(self)
+            .with_context(|| "Failed to serialize profiles")?;
+        state::write_atomic(&datadir.join(PROFILES_FILE_NAME), &contents)
+            .with_context(|| "Failed to write profiles")
+    }
+}
@@ -0,0 +1,164 @@
+//! Rules renaming entries while patching.
+//!
+//! An `_ice` patch directory may contain a `rename.toml` of rules, applied in
+//! order to the name of every entry of the original ICE file and of every file
+//! of the patch before they are matched up:
+//!
+//! ```toml
+//! [[rule]]
+//! from = "_123."
+//! to = "_456."
+//!
+//! [[rule]]
+//! regex = '^pl_(\w+)_789\.(aqp|aqn)$'
+//! to = "pl_${1}_790.$2"
+//! ```
+//!
+//! `from` replaces every occurrence of a literal string. `regex` replaces every
+//! match of a regular expression, and `to` may refer to its groups. Both match
+//! without regard to case, like entry names.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use regex::{NoExpand, Regex, RegexBuilder};
+use serde::Deserialize;
+
+/// File name of the rename rules within an `_ice` patch directory.
+pub const RENAME_FILE_NAME: &str = "rename.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenameFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    from: Option<String>,
+    regex: Option<String>,
+    to: String,
+}
+
+pub struct RenameRules {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    pattern: Regex,
+    to: String,
+    /// Whether `to` is inserted as is, rather than expanding group references.
+    literal: bool,
+}
+
+impl RenameRules {
+    /// Load the rename rules of an `_ice` patch directory, if it has any.
+    pub fn load(patch_src: &Path) -> anyhow::Result<Option<RenameRules>> {
+        let path = patch_src.join(RENAME_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read rename rules {}", path.to_string_lossy()))?;
+        RenameRules::parse(&contents, &path).map(Some)
+    }
+
+    /// Parse the contents of the rename rules file at `path`.
+    fn parse(contents: &str, path: &Path) -> anyhow::Result<RenameRules> {
+        let file: RenameFile = toml::from_str(contents)
+            .with_context(|| format!("Failed to parse rename rules {}", path.to_string_lossy()))?;
+
+        let mut rules = Vec::new();
+        for spec in file.rules {
+            let (pattern, literal) = match (&spec.from, &spec.regex) {
+                (Some(from), None) if !from.is_empty() => (regex::escape(from), true),
+                (None, Some(regex)) => (regex.clone(), false),
+                _ => bail!(
+                    "Rule to {:?} in {} needs either a non-empty from or a regex",
+                    spec.to,
+                    path.to_string_lossy(),
+                ),
+            };
+            let pattern = RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .with_context(|| format!("Invalid regex {:?} in {}", pattern, path.to_string_lossy()))?;
+            rules.push(Rule { pattern, to: spec.to, literal });
+        }
+        Ok(RenameRules { rules })
+    }
+
+    /// The name `name` is renamed to.
+    pub fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let mut name = Cow::Borrowed(name);
+        for rule in self.rules.iter() {
+            let renamed = if rule.literal {
+                rule.pattern.replace_all(&name, NoExpand(&rule.to))
+            } else {
+                rule.pattern.replace_all(&name, rule.to.as_str())
+            };
+            if let Cow::Owned(renamed) = renamed {
+                name = Cow::Owned(renamed);
+            }
+        }
+        name
+    }
+
+    /// Describe the rules, one per line, for telling sets of rules apart.
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for rule in self.rules.iter() {
+            let kind = if rule.literal { "from" } else { "regex" };
+            description.push_str(&format!("{} {:?} to {:?}\n", kind, rule.pattern.as_str(), rule.to));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(contents: &str) -> RenameRules {
+        RenameRules::parse(contents, Path::new(RENAME_FILE_NAME)).unwrap()
+    }
+
+    #[test]
+    fn literal_rules_replace_every_occurrence_without_expanding() {
+        let rules = rules("[[rule]]\nfrom = \"_123\"\nto = \"_$1\"\n");
+        assert_eq!(rules.apply("pl_123_123.aqp"), "pl_$1_$1.aqp");
+    }
+
+    #[test]
+    fn literal_rules_ignore_case_and_regex_syntax() {
+        let rules = rules("[[rule]]\nfrom = \"A.AQP\"\nto = \"b.aqp\"\n");
+        assert_eq!(rules.apply("pl_a.aqp"), "pl_b.aqp");
+        assert_eq!(rules.apply("pl_axaqp"), "pl_axaqp");
+    }
+
+    #[test]
+    fn regex_rules_expand_groups() {
+        let rules = rules("[[rule]]\nregex = '^pl_(\\w+)_789\\.(aqp|aqn)$'\nto = \"pl_${1}_790.$2\"\n");
+        assert_eq!(rules.apply("pl_head_789.aqp"), "pl_head_790.aqp");
+        assert_eq!(rules.apply("PL_head_789.AQN"), "pl_head_790.AQN");
+        assert_eq!(rules.apply("pl_head_788.aqp"), "pl_head_788.aqp");
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let rules = rules("[[rule]]\nfrom = \"a\"\nto = \"b\"\n\n[[rule]]\nfrom = \"b\"\nto = \"c\"\n");
+        assert_eq!(rules.apply("a.dds"), "c.dds");
+    }
+
+    #[test]
+    fn rules_need_exactly_one_pattern() {
+        let path = Path::new(RENAME_FILE_NAME);
+        assert!(RenameRules::parse("[[rule]]\nto = \"x\"\n", path).is_err());
+        assert!(RenameRules::parse("[[rule]]\nfrom = \"a\"\nregex = \"a\"\nto = \"x\"\n", path).is_err());
+        assert!(RenameRules::parse("[[rule]]\nfrom = \"\"\nto = \"x\"\n", path).is_err());
+        assert!(RenameRules::parse("[[rule]]\nregex = \"(\"\nto = \"x\"\n", path).is_err());
+    }
+}
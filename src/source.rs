@@ -0,0 +1,159 @@
+//! The files of a patch directory that go into ICE groups.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::rename::RenameRules;
+use crate::state::InstallState;
+use crate::swap::{Extracted, Swap};
+
+/// The files in a group directory of a patch, matched to entries by base name
+/// without regard to case as Windows would. Subdirectories are only for
+/// organisation; their files are found recursively.
+#[derive(Default)]
+pub struct GroupFiles {
+    /// Files keyed by the lowercased name of the entry they provide.
+    files: BTreeMap<String, PatchFile>,
+}
+
+/// A file of a patch and the entry it provides.
+pub struct PatchFile {
+    /// Name of the entry, which is the name of the file unless it was renamed.
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl GroupFiles {
+    /// Scan a group directory. A missing directory has no files. Fails if two
+    /// files would provide the same entry.
+    pub fn scan(dir: &Path) -> anyhow::Result<GroupFiles> {
+        let mut group_files = GroupFiles::default();
+        if dir.exists() {
+            group_files.scan_dir(dir)?;
+        }
+        Ok(group_files)
+    }
+
+    fn scan_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let read_dir = dir.read_dir()
+            .with_context(|| format!("Unable to read dir {}", dir.to_string_lossy()))?;
+        for file in read_dir {
+            let file = file
+                .with_context(|| format!("Unable to index file while reading dir {}", dir.to_string_lossy()))?;
+            let path = file.path();
+            if path.is_dir() {
+                self.scan_dir(&path)?;
+                continue;
+            }
+
+            let name = file.file_name().to_string_lossy().into_owned();
+            let key = name.to_lowercase();
+            if let Some(PatchFile { path: other, .. }) = self.files.get(&key) {
+                if other.file_name() == path.file_name() {
+                    bail!(
+                        "{} and {} would both replace the entry {}",
+                        other.to_string_lossy(),
+                        path.to_string_lossy(),
+                        file.file_name().to_string_lossy(),
+                    );
+                }
+                bail!(
+                    "{} and {} differ only by case and would replace the same entry",
+                    other.to_string_lossy(),
+                    path.to_string_lossy(),
+                );
+            }
+            self.files.insert(key, PatchFile { name, path });
+        }
+        Ok(())
+    }
+
+    /// Rename the entries the files provide. Fails if two files would provide
+    /// the same entry.
+    pub fn rename(&mut self, rules: &RenameRules) -> anyhow::Result<()> {
+        let mut renamed: BTreeMap<String, PatchFile> = BTreeMap::new();
+        for (_, mut file) in std::mem::take(&mut self.files) {
+            file.name = rules.apply(&file.name).into_owned();
+            if let Some(other) = renamed.get(&file.name.to_lowercase()) {
+                bail!(
+                    "{} and {} would both be renamed to the entry {}",
+                    other.path.to_string_lossy(),
+                    file.path.to_string_lossy(),
+                    file.name,
+                );
+            }
+            renamed.insert(file.name.to_lowercase(), file);
+        }
+        self.files = renamed;
+        Ok(())
+    }
+
+    /// Add the files of `other` for entries not already provided here.
+    pub fn extend_missing(&mut self, other: GroupFiles) {
+        for (key, file) in other.files {
+            self.files.entry(key).or_insert(file);
+        }
+    }
+
+    /// Keep only the files for which `keep` returns true.
+    pub fn retain<F: FnMut(&PatchFile) -> bool>(&mut self, mut keep: F) {
+        self.files.retain(|_, f| keep(f));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Find the file for the entry `name`.
+    pub fn get(&self, name: &str) -> Option<&PatchFile> {
+        self.files.get(&name.to_lowercase())
+    }
+
+    /// Iterate over every file, along with the lowercased name of its entry.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PatchFile)> {
+        self.files.iter().map(|(k, f)| (k.as_str(), f))
+    }
+}
+
+/// Scan the files an `_ice` patch directory provides for each group, including
+/// the entries of its swap, if it has one, copied from the data directory
+/// `datadir` with install state `state`, and renamed by its rename rules, if
+/// it has any. The copied entries are only there until the returned
+/// [`Extracted`] is dropped.
+pub fn scan_patch(patch_src: &Path, datadir: &Path, state: &InstallState) -> anyhow::Result<([GroupFiles; 2], Option<Extracted>)> {
+    let src_1 = patch_src.join("1");
+    let src_2 = patch_src.join("2");
+    if src_1.exists() && !src_1.is_dir() {
+        bail!("1 in patch directory {} is not a directory", patch_src.to_string_lossy());
+    }
+    if src_2.exists() && !src_2.is_dir() {
+        bail!("2 in patch directory {} is not a directory", patch_src.to_string_lossy());
+    }
+
+    let swap = Swap::load(patch_src)?;
+    let rules = RenameRules::load(patch_src)?;
+    if !src_1.exists() && !src_2.exists() && swap.is_none() && rules.is_none() {
+        bail!("Patch directory {} does not contain any files to patch", patch_src.to_string_lossy());
+    }
+
+    let mut files = [GroupFiles::scan(&src_1)?, GroupFiles::scan(&src_2)?];
+    let extracted = match swap {
+        Some(swap) => {
+            let extracted = swap.extract(datadir, state)
+                .with_context(|| format!("Failed to copy the entries swapped in by {}", patch_src.to_string_lossy()))?;
+            for (i, group_files) in files.iter_mut().enumerate() {
+                group_files.extend_missing(GroupFiles::scan(&extracted.dir().join((i + 1).to_string()))?);
+            }
+            Some(extracted)
+        },
+        None => None,
+    };
+    if let Some(rules) = rules {
+        for group_files in files.iter_mut() {
+            group_files.rename(&rules)?;
+        }
+    }
+    Ok((files, extracted))
+}
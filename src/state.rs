@@ -0,0 +1,161 @@
+//! Record of what has been installed into a data directory.
+//!
+//! The state is kept next to the data it describes so that later runs can tell
+//! our own output apart from files the game client has since replaced.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::PatchFilter;
+use crate::manifest::Selection;
+
+/// File name of the install state within a data directory.
+const STATE_FILE_NAME: &str = "modpatcher-state.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InstallState {
+    /// Patched ICE archives, keyed by their `/`-separated path relative to the
+    /// data directory.
+    pub ices: BTreeMap<String, IceRecord>,
+    /// Name of the profile last switched to, if any.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Components and variants last installed from each patch directory with
+    /// a manifest, keyed by its canonical path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub packages: BTreeMap<PathBuf, Selection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceRecord {
+    /// `_ice` patch directories applied to the archive, in order.
+    pub sources: Vec<PathBuf>,
+    /// Filters that sources were applied with, for those only partly applied.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub filters: BTreeMap<PathBuf, PatchFilter>,
+    /// Hash of the archive before the first source was applied, or `None` if
+    /// the archive was created by us.
+    pub original_hash: Option<String>,
+    /// Hash of the archive as last written by us.
+    pub patched_hash: String,
+    /// Where the original archive was backed up to, if it was.
+    pub backup: Option<PathBuf>,
+    /// Seconds since the Unix epoch at which the archive was last written.
+    pub patched_at: u64,
+}
+
+impl InstallState {
+    /// Load the install state of a data directory, or an empty state if none
+    /// has been recorded yet.
+    pub fn load(datadir: &Path) -> anyhow::Result<InstallState> {
+        let path = datadir.join(STATE_FILE_NAME);
+        if !path.exists() {
+            return Ok(InstallState::default());
+        }
+        let contents = std::fs::read(&path)
+            .with_context(|| format!("Failed to read install state {}", path.to_string_lossy()))?;
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse install state {}", path.to_string_lossy()))
+    }
+
+    pub fn save(&self, datadir: &Path) -> anyhow::Result<()> {
+        let contents = serde_json::to_vec_pretty(self)
+            .with_context(|| "Failed to serialize install state")?;
+        write_atomic(&datadir.join(STATE_FILE_NAME), &contents)
+            .with_context(|| "Failed to write install state")
+    }
+}
+
+/// Key of a file within a data directory, as used by [`InstallState::ices`].
+pub fn key(datadir: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(datadir).unwrap_or(path);
+    let parts: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    parts.join("/")
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The UTC year, month, day, hour, minute and second of a time in seconds since
+/// the Unix epoch.
+pub fn utc(secs: u64) -> (i64, u64, u64, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u64, day as u64, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// A time in seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, hour, minute, second)
+}
+
+/// Replace the file at `path` with `contents`, so that it is never left half
+/// written.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut partial_name = path.file_name().unwrap().to_os_string();
+    partial_name.push(".partial");
+    let partial = path.with_file_name(partial_name);
+    std::fs::write(&partial, contents)
+        .with_context(|| format!("Failed to write {}", partial.to_string_lossy()))?;
+    std::fs::rename(&partial, path)
+        .with_context(|| format!("Failed to move {} into place", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_of_the_epoch() {
+        assert_eq!(utc(0), (1970, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn utc_around_leap_days() {
+        // 2000-02-29 23:59:59, in a leap year divisible by 400
+        assert_eq!(utc(951_868_799), (2000, 2, 29, 23, 59, 59));
+        assert_eq!(utc(951_868_800), (2000, 3, 1, 0, 0, 0));
+        // 2024-02-29 12:34:56
+        assert_eq!(utc(1_709_210_096), (2024, 2, 29, 12, 34, 56));
+        // 2100-03-01, as 2100 is not a leap year
+        assert_eq!(utc(4_107_542_400), (2100, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn utc_at_the_end_of_a_year() {
+        assert_eq!(utc(1_704_067_199), (2023, 12, 31, 23, 59, 59));
+        assert_eq!(utc(1_704_067_200), (2024, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn format_time_pads_fields() {
+        assert_eq!(format_time(1_704_164_645), "2024-01-02 03:04:05 UTC");
+    }
+
+    #[test]
+    fn keys_are_slash_separated_and_relative() {
+        let datadir = Path::new("data");
+        assert_eq!(key(datadir, &datadir.join("win32reboot").join("01").join("23")), "win32reboot/01/23");
+    }
+}
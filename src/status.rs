@@ -0,0 +1,68 @@
+//! Reporting what has been installed into a data directory.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::hash;
+use crate::state::{self, InstallState};
+
+/// Write every ICE file recorded in the install state to `out`, with the
+/// patches applied to it and whether it is still as we wrote it.
+pub fn status(datadir: &Path, state: &InstallState, out: &mut impl Write) -> anyhow::Result<()> {
+    if let Some(profile) = &state.profile {
+        writeln!(out, "Profile: {}", profile)?;
+    }
+    for (package, selection) in state.packages.iter() {
+        writeln!(out, "Package {}: {}", package.to_string_lossy(), selection.describe())?;
+    }
+    if state.ices.is_empty() {
+        writeln!(out, "No ICE files have been patched in {}", datadir.to_string_lossy())?;
+        return Ok(());
+    }
+
+    let mut changed = 0;
+    let mut sources = BTreeSet::new();
+    for (key, record) in state.ices.iter() {
+        let ice_path = datadir.join(key);
+        let condition = if !ice_path.is_file() {
+            changed += 1;
+            "missing"
+        } else {
+            let current_hash = hash::hash_file(&ice_path)
+                .with_context(|| format!("Failed to hash {}", ice_path.to_string_lossy()))?;
+            if current_hash == record.patched_hash {
+                "as patched"
+            } else {
+                changed += 1;
+                "replaced since it was patched; run reapply"
+            }
+        };
+
+        let origin = if record.original_hash.is_some() { "modified" } else { "created" };
+        writeln!(out, "{}: {}, {}", key, origin, condition)?;
+        writeln!(out, "  patched at {}", state::format_time(record.patched_at))?;
+        for source in record.sources.iter() {
+            if record.filters.contains_key(source) {
+                writeln!(out, "  from {} (filtered)", source.to_string_lossy())?;
+            } else {
+                writeln!(out, "  from {}", source.to_string_lossy())?;
+            }
+            sources.insert(source);
+        }
+        match &record.backup {
+            Some(backup) if backup.is_file() => writeln!(out, "  backup {}", backup.to_string_lossy())?,
+            Some(backup) => writeln!(out, "  backup {} (missing)", backup.to_string_lossy())?,
+            None => writeln!(out, "  no backup")?,
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{} ICE files patched from {} patch sources", state.ices.len(), sources.len())?;
+    if changed > 0 {
+        writeln!(out, "{} of them no longer match what was written", changed)?;
+    }
+    Ok(())
+}
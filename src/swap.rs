@@ -0,0 +1,180 @@
+//! Swaps, which patch an ICE file with entries copied from another.
+//!
+//! An `_ice` patch directory may contain a `swap.toml` naming an ICE file of
+//! the data directory to copy entries from, so that a swap can be shared
+//! without any game files:
+//!
+//! ```toml
+//! source = "win32/0123456789abcdef0123456789abcdef"
+//! entries = ["pl_a_123.aqp", "pl_a_123.aqn"]
+//! rename = { "pl_a_123.aqp" = "pl_a_456.aqp", "pl_a_123.aqn" = "pl_a_456.aqn" }
+//! ```
+//!
+//! Without `entries`, every entry is copied. Entries are copied into the same
+//! group they are in in the source, as if they were files of the patch
+//! directory. Files in the patch directory's `1` and `2` directories take
+//! precedence over copied entries of the same name. If the source has been
+//! patched, entries are copied from its backed-up original instead.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::ice::{self, IceEntry};
+use crate::state::{self, InstallState};
+
+/// File name of a swap within an `_ice` patch directory.
+pub const SWAP_FILE_NAME: &str = "swap.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Swap {
+    /// `/`-separated path of the ICE file to copy from, relative to the data
+    /// directory.
+    pub source: String,
+    /// Names of the entries to copy, or `None` to copy all of them.
+    #[serde(default)]
+    pub entries: Option<Vec<String>>,
+    /// New names of copied entries, keyed by their name in the source.
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+}
+
+/// Entries of a swap's source written out as patch files, removed again when
+/// dropped.
+pub struct Extracted {
+    dir: PathBuf,
+    /// Name of the entry of the source each file was written from, which
+    /// differs from the file's name if it was renamed.
+    source_names: HashMap<PathBuf, String>,
+}
+
+impl Swap {
+    /// Load the swap of an `_ice` patch directory, if it has one.
+    pub fn load(patch_src: &Path) -> anyhow::Result<Option<Swap>> {
+        let path = patch_src.join(SWAP_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read swap {}", path.to_string_lossy()))?;
+        let swap = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse swap {}", path.to_string_lossy()))?;
+        Ok(Some(swap))
+    }
+
+    /// Path of the unpatched source, or of the source as it is if it was
+    /// patched without a backup.
+    fn source_path(&self, datadir: &Path, state: &InstallState) -> PathBuf {
+        let live = datadir.join(&self.source);
+        match state.ices.get(&state::key(datadir, &live)) {
+            Some(record) => match &record.backup {
+                Some(backup) if backup.is_file() => backup.clone(),
+                _ => {
+                    eprintln!(
+                        "Warning: swap source {} has been patched and its original is not backed up; copying from it as patched",
+                        live.to_string_lossy(),
+                    );
+                    live
+                },
+            },
+            None => live,
+        }
+    }
+
+    /// The entries of each group of the source to copy, with the names they
+    /// are copied as.
+    fn select<'a>(&self, groups: &'a [Vec<IceEntry>; 2]) -> anyhow::Result<[Vec<(String, &'a IceEntry)>; 2]> {
+        let wanted: Option<HashSet<String>> = self.entries
+            .as_ref()
+            .map(|e| e.iter().map(|n| n.to_lowercase()).collect());
+        let rename: BTreeMap<String, &str> = self.rename
+            .iter()
+            .map(|(from, to)| (from.to_lowercase(), to.as_str()))
+            .collect();
+
+        let mut selected: [Vec<(String, &IceEntry)>; 2] = Default::default();
+        let mut found = HashSet::new();
+        for (i, entries) in groups.iter().enumerate() {
+            let mut names = HashSet::new();
+            for entry in entries {
+                let key = entry.name.to_lowercase();
+                if wanted.as_ref().is_some_and(|w| !w.contains(&key)) {
+                    continue;
+                }
+                let name = rename.get(&key).map(|n| n.to_string()).unwrap_or_else(|| entry.name.clone());
+                if !names.insert(name.to_lowercase()) {
+                    bail!("Several entries of group {} of {} would be copied as {}", i + 1, self.source, name);
+                }
+                found.insert(key);
+                selected[i].push((name, entry));
+            }
+        }
+
+        for name in self.entries.iter().flatten().chain(self.rename.keys()) {
+            if !found.contains(&name.to_lowercase()) {
+                bail!("{} has no entry {} to copy", self.source, name);
+            }
+        }
+        Ok(selected)
+    }
+
+    /// Write the entries to copy from the source in `datadir` to a temporary
+    /// directory, as the group directories `1` and `2` of a patch. A source
+    /// patched according to `state` is read from its backup.
+    pub fn extract(&self, datadir: &Path, state: &InstallState) -> anyhow::Result<Extracted> {
+        let source_path = self.source_path(datadir, state);
+        if !source_path.is_file() {
+            bail!("Swap source {} does not exist", source_path.to_string_lossy());
+        }
+        let source = ice::read_ice(&source_path)?;
+        let selected = self.select(&source.groups)?;
+
+        // unique within the process, as a swap may be extracted while another
+        // is still in use
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "pso2-modpatcher-{}-swap-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+        ));
+        let mut extracted = Extracted { dir, source_names: HashMap::new() };
+        for (i, entries) in selected.iter().enumerate() {
+            let group_dir = extracted.dir.join((i + 1).to_string());
+            std::fs::create_dir_all(&group_dir)
+                .with_context(|| format!("Failed to make directory {}", group_dir.to_string_lossy()))?;
+            for (name, entry) in entries {
+                if name.contains(['/', '\\']) {
+                    bail!("{} is not a valid entry name", name);
+                }
+                let path = group_dir.join(name);
+                std::fs::write(&path, &entry.data)
+                    .with_context(|| format!("Failed to write {} from {}", path.to_string_lossy(), self.source))?;
+                extracted.source_names.insert(path, entry.name.clone());
+            }
+        }
+        Ok(extracted)
+    }
+}
+
+impl Extracted {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Name of the entry of the source that the file at `path` was written
+    /// from, if it was.
+    pub fn source_name(&self, path: &Path) -> Option<&str> {
+        self.source_names.get(path).map(|n| n.as_str())
+    }
+}
+
+impl Drop for Extracted {
+    fn drop(&mut self) {
+        // best effort; it is in the temporary directory either way
+        let _e = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,298 @@
+//! Sanity checks on files before they are written into an ICE archive.
+//!
+//! A file in the wrong format or cut short is accepted by the archive but
+//! crashes the client, so we look for the obvious cases up front.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+/// How validation problems are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationLevel {
+    Off,
+    Warn,
+    Error,
+}
+
+impl FromStr for ValidationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ValidationLevel, String> {
+        match s {
+            "off" => Ok(ValidationLevel::Off),
+            "warn" => Ok(ValidationLevel::Warn),
+            "error" => Ok(ValidationLevel::Error),
+            _ => Err(format!("unknown validation level {:?}", s)),
+        }
+    }
+}
+
+/// A check on the contents of files with particular extensions.
+pub trait Validator {
+    /// Return a description of the problem if `data` is not a plausible file.
+    fn validate(&self, data: &[u8]) -> Result<(), String>;
+}
+
+/// Extensions expected for files starting with a known magic.
+const MAGICS: &[(&[u8], &[&str])] = &[
+    (b"DDS ", &["dds"]),
+    (b"NIFL", MODEL_EXTS),
+    (b"VTBF", MODEL_EXTS),
+    (b"AFP\0", MODEL_EXTS),
+    (b"@UTF", &["acb", "cpk"]),
+    (b"AFS2", &["awb"]),
+    (b"CRID", &["usm"]),
+    (b"\x89PNG", &["png"]),
+    (b"ICE\0", &["ice"]),
+];
+
+const MODEL_EXTS: &[&str] = &["aqp", "aqn", "aqo", "trp", "trn", "tro"];
+
+/// The validators run on files written into archives, by extension.
+pub struct Validators {
+    level: ValidationLevel,
+    by_ext: HashMap<String, Box<dyn Validator>>,
+}
+
+impl Validators {
+    /// Create the set of built-in validators.
+    pub fn new(level: ValidationLevel) -> Validators {
+        let mut validators = Validators {
+            level,
+            by_ext: HashMap::new(),
+        };
+        validators.register("dds", DdsValidator);
+        for ext in MODEL_EXTS.iter() {
+            validators.register(ext, ModelValidator);
+        }
+        validators
+    }
+
+    /// Use `validator` for files with the extension `ext`, replacing any
+    /// validator already registered for it.
+    pub fn register<V: Validator + 'static>(&mut self, ext: &str, validator: V) {
+        self.by_ext.insert(ext.to_ascii_lowercase(), Box::new(validator));
+    }
+
+    /// Read and validate the file at `path`, to be written as an entry with
+    /// extension `ext`, warning about or failing on any problems found.
+    pub fn check_file(&self, path: &Path, ext: &str) -> anyhow::Result<()> {
+        if self.level == ValidationLevel::Off {
+            return Ok(());
+        }
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read {} to validate it", path.to_string_lossy()))?;
+        self.check(path, ext, &data)
+    }
+
+    /// Validate a file about to be written as an entry with extension `ext`,
+    /// warning about or failing on any problems found.
+    pub fn check(&self, path: &Path, ext: &str, data: &[u8]) -> anyhow::Result<()> {
+        if self.level == ValidationLevel::Off {
+            return Ok(());
+        }
+
+        let ext = ext.to_ascii_lowercase();
+        let mut problems = Vec::new();
+        if let Some((_, exts)) = MAGICS.iter().find(|(magic, _)| data.starts_with(magic)) {
+            if !exts.contains(&ext.as_str()) {
+                problems.push(format!("contents look like a .{} file, not .{}", exts[0], ext));
+            }
+        }
+        if let Some(validator) = self.by_ext.get(&ext) {
+            if let Err(e) = validator.validate(data) {
+                problems.push(e);
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let message = format!("{} failed validation: {}", path.to_string_lossy(), problems.join("; "));
+        if self.level == ValidationLevel::Error {
+            bail!(message);
+        }
+        eprintln!("Warning: {}", message);
+        Ok(())
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Checks the DDS header and that the top mip level is present.
+struct DdsValidator;
+
+impl Validator for DdsValidator {
+    fn validate(&self, data: &[u8]) -> Result<(), String> {
+        const HEADER_LEN: usize = 128;
+        const DX10_HEADER_LEN: usize = 20;
+        const DDPF_FOURCC: u32 = 0x4;
+        const DDPF_RGB: u32 = 0x40;
+
+        if data.len() < HEADER_LEN {
+            return Err(format!("DDS file is {} bytes, shorter than its header", data.len()));
+        }
+        if &data[..4] != b"DDS " {
+            return Err("missing DDS magic".to_owned());
+        }
+        if read_u32(data, 4) != 124 {
+            return Err(format!("DDS header size is {}, expected 124", read_u32(data, 4)));
+        }
+        if read_u32(data, 76) != 32 {
+            return Err(format!("DDS pixel format size is {}, expected 32", read_u32(data, 76)));
+        }
+
+        let height = read_u32(data, 12) as usize;
+        let width = read_u32(data, 16) as usize;
+        if width == 0 || height == 0 {
+            return Err(format!("DDS dimensions {}x{} are empty", width, height));
+        }
+
+        let pf_flags = read_u32(data, 80);
+        let four_cc = &data[84..88];
+        let mut data_start = HEADER_LEN;
+        // `None` inside if the dimensions are too large to compute it
+        let top_level_len = if pf_flags & DDPF_FOURCC != 0 {
+            let block_size = match four_cc {
+                b"DXT1" | b"ATI1" | b"BC4U" => Some(8),
+                b"DXT2" | b"DXT3" | b"DXT4" | b"DXT5" | b"ATI2" | b"BC5U" => Some(16),
+                b"DX10" => {
+                    data_start += DX10_HEADER_LEN;
+                    None
+                },
+                _ => None,
+            };
+            block_size.map(|b| width.div_ceil(4).checked_mul(height.div_ceil(4))?.checked_mul(b))
+        } else if pf_flags & DDPF_RGB != 0 {
+            let bits = width.checked_mul(height).and_then(|n| n.checked_mul(read_u32(data, 88) as usize));
+            Some(bits.map(|bits| bits / 8))
+        } else {
+            None
+        };
+        let top_level_len = top_level_len
+            .map(|len| len.ok_or_else(|| "DDS header dimensions overflow".to_owned()))
+            .transpose()?;
+
+        if data.len() < data_start {
+            return Err("DDS file is shorter than its DX10 header".to_owned());
+        }
+        if let Some(len) = top_level_len {
+            if data.len() - data_start < len {
+                return Err(format!(
+                    "DDS data is {} bytes, but a {}x{} image needs at least {}",
+                    data.len() - data_start, width, height, len,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Magics model files may start with.
+const MODEL_MAGICS: &[&[u8]] = &[b"NIFL", b"VTBF", b"AFP\0"];
+
+/// Checks model files carry one of [`MODEL_MAGICS`].
+struct ModelValidator;
+
+impl Validator for ModelValidator {
+    fn validate(&self, data: &[u8]) -> Result<(), String> {
+        if MODEL_MAGICS.iter().any(|m| data.starts_with(m)) {
+            Ok(())
+        } else {
+            Err("missing NIFL, VTBF or AFP magic".to_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DDS header for a `width`x`height` image with the pixel format flags
+    /// `pf_flags`, followed by `data_len` bytes of image data.
+    fn dds(width: u32, height: u32, pf_flags: u32, four_cc: &[u8; 4], rgb_bits: u32, data_len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; 128];
+        data[..4].copy_from_slice(b"DDS ");
+        data[4..8].copy_from_slice(&124u32.to_le_bytes());
+        data[12..16].copy_from_slice(&height.to_le_bytes());
+        data[16..20].copy_from_slice(&width.to_le_bytes());
+        data[76..80].copy_from_slice(&32u32.to_le_bytes());
+        data[80..84].copy_from_slice(&pf_flags.to_le_bytes());
+        data[84..88].copy_from_slice(four_cc);
+        data[88..92].copy_from_slice(&rgb_bits.to_le_bytes());
+        data.resize(128 + data_len, 0);
+        data
+    }
+
+    #[test]
+    fn dxt1_needs_8_bytes_per_block() {
+        // 5x3 rounds up to 2x1 blocks
+        assert!(DdsValidator.validate(&dds(5, 3, 0x4, b"DXT1", 0, 16)).is_ok());
+        assert!(DdsValidator.validate(&dds(5, 3, 0x4, b"DXT1", 0, 15)).is_err());
+        assert!(DdsValidator.validate(&dds(256, 256, 0x4, b"DXT1", 0, 32768)).is_ok());
+    }
+
+    #[test]
+    fn huge_dimensions_are_a_problem_not_a_panic() {
+        for (pf_flags, four_cc, rgb_bits) in [(0x4, b"DXT5", 0), (0x40, b"\0\0\0\0", 32)].iter() {
+            let e = DdsValidator.validate(&dds(u32::MAX, u32::MAX, *pf_flags, four_cc, *rgb_bits, 0)).unwrap_err();
+            assert_eq!(e, "DDS header dimensions overflow");
+        }
+    }
+
+    #[test]
+    fn dxt5_needs_16_bytes_per_block() {
+        assert!(DdsValidator.validate(&dds(8, 8, 0x4, b"DXT5", 0, 64)).is_ok());
+        assert!(DdsValidator.validate(&dds(8, 8, 0x4, b"DXT5", 0, 63)).is_err());
+    }
+
+    #[test]
+    fn rgb_size_comes_from_the_bit_count() {
+        assert!(DdsValidator.validate(&dds(4, 4, 0x40, b"\0\0\0\0", 32, 64)).is_ok());
+        assert!(DdsValidator.validate(&dds(4, 4, 0x40, b"\0\0\0\0", 32, 63)).is_err());
+        assert!(DdsValidator.validate(&dds(4, 4, 0x40, b"\0\0\0\0", 24, 48)).is_ok());
+    }
+
+    #[test]
+    fn dx10_only_needs_its_extra_header() {
+        assert!(DdsValidator.validate(&dds(4, 4, 0x4, b"DX10", 0, 20)).is_ok());
+        assert!(DdsValidator.validate(&dds(4, 4, 0x4, b"DX10", 0, 19)).is_err());
+    }
+
+    #[test]
+    fn broken_headers_are_rejected() {
+        assert!(DdsValidator.validate(&dds(4, 4, 0x4, b"DXT1", 0, 8)[..127]).is_err());
+        assert!(DdsValidator.validate(&dds(0, 4, 0x4, b"DXT1", 0, 8)).is_err());
+        let mut bad_size = dds(4, 4, 0x4, b"DXT1", 0, 8);
+        bad_size[4] = 123;
+        assert!(DdsValidator.validate(&bad_size).is_err());
+    }
+
+    #[test]
+    fn contents_must_match_the_extension() {
+        let validators = Validators::new(ValidationLevel::Error);
+        let path = Path::new("tex.aqp");
+        assert!(validators.check(path, "aqp", b"NIFL....").is_ok());
+        assert!(validators.check(path, "aqp", &dds(4, 4, 0x4, b"DXT1", 0, 8)).is_err());
+        assert!(validators.check(path, "AQP", b"junk").is_err());
+        assert!(validators.check(path, "txt", b"junk").is_ok());
+        assert!(Validators::new(ValidationLevel::Warn).check(path, "aqp", b"junk").is_ok());
+        assert!(Validators::new(ValidationLevel::Off).check(path, "aqp", b"junk").is_ok());
+    }
+
+    #[test]
+    fn models_need_a_known_magic() {
+        for magic in MODEL_MAGICS {
+            assert!(ModelValidator.validate(magic).is_ok());
+        }
+        let e = ModelValidator.validate(b"junk").unwrap_err();
+        assert_eq!(e, "missing NIFL, VTBF or AFP magic");
+    }
+}
@@ -0,0 +1,171 @@
+//! Checking that installed patches are still in place.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::hash;
+use crate::ice::{self, IceContents};
+use crate::rename::RenameRules;
+use crate::source;
+use crate::state::{IceRecord, InstallState};
+
+/// Verify every ICE file recorded in the install state, printing any drift
+/// found. Returns the number of ICE files with problems.
+pub fn verify(datadir: &Path, state: &InstallState, verbose: bool) -> anyhow::Result<usize> {
+    let mut drifted = 0;
+    for (key, record) in state.ices.iter() {
+        let ice_path = datadir.join(key);
+        let problems = verify_ice(datadir, state, &ice_path, key, record)
+            .with_context(|| format!("Failed to verify {}", ice_path.to_string_lossy()))?;
+        if problems.is_empty() {
+            if verbose {
+                eprintln!("{}: ok", key);
+            }
+        } else {
+            drifted += 1;
+            println!("{}:", key);
+            for problem in problems {
+                println!("  {}", problem);
+            }
+        }
+    }
+    Ok(drifted)
+}
+
+fn verify_ice(datadir: &Path, state: &InstallState, ice_path: &Path, key: &str, record: &IceRecord) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    if !ice_path.is_file() {
+        problems.push("missing from the data directory".to_owned());
+        return Ok(problems);
+    }
+
+    let current = match ice::read_ice(ice_path) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(format!("does not load: {:#}", e));
+            return Ok(problems);
+        },
+    };
+
+    let current_hash = hash::hash_file(ice_path)
+        .with_context(|| format!("Failed to hash {}", ice_path.to_string_lossy()))?;
+    if current_hash != record.patched_hash {
+        problems.push("changed since it was patched (replaced by a game update? try reapply)".to_owned());
+        return Ok(problems);
+    }
+
+    // later sources win, as they were applied on top of earlier ones. Keyed by
+    // lowercased name, as files replace entries without regard to case
+    let mut expected: [HashMap<String, PathBuf>; 2] = Default::default();
+    // entries copied by swaps, kept until the comparisons are done
+    let mut extracted = Vec::new();
+    let mut renames = Vec::new();
+    for source in record.sources.iter() {
+        if !source.is_dir() {
+            problems.push(format!("patch source {} is missing", source.to_string_lossy()));
+            continue;
+        }
+        let (mut groups, source_extracted) = match source::scan_patch(source, datadir, state) {
+            Ok(f) => f,
+            Err(e) => {
+                problems.push(format!("patch source {} can't be read: {:#}", source.to_string_lossy(), e));
+                continue;
+            },
+        };
+        extracted.extend(source_extracted.map(|e| (e, source)));
+        match RenameRules::load(source) {
+            Ok(Some(rules)) => {
+                // entries patched in by earlier sources are renamed along with
+                // the rest of the ICE, before this source's files are matched
+                for expected in expected.iter_mut() {
+                    *expected = std::mem::take(expected)
+                        .into_iter()
+                        .map(|(name, path)| (rules.apply(&name).to_lowercase(), path))
+                        .collect();
+                }
+                renames.push(rules);
+            },
+            Ok(None) => {},
+            Err(e) => problems.push(format!("{:#}", e)),
+        }
+        for (i, files) in groups.iter_mut().enumerate() {
+            if let Some(filter) = record.filters.get(source) {
+                files.retain(|f| filter.includes(key, i + 1, &f.name));
+            }
+            for (key, file) in files.iter() {
+                if file.path.is_file() {
+                    expected[i].insert(key.to_owned(), file.path.clone());
+                }
+            }
+        }
+    }
+
+    // copied entries are described by where they were copied from, as the
+    // files they were extracted to are temporary
+    let describe = |path: &Path| match extracted.iter().find(|(e, _)| path.starts_with(e.dir())) {
+        Some((_, source)) => format!("the entry swapped in by {}", source.to_string_lossy()),
+        None => path.to_string_lossy().into_owned(),
+    };
+    for &group in ice::GROUPS.iter() {
+        let entries = current.group(group);
+        for (name, path) in expected[ice::group_index(group)].iter() {
+            let contents = std::fs::read(path)
+                .with_context(|| format!("Failed to read patch file {}", path.to_string_lossy()))?;
+            match entries.iter().find(|e| &e.name.to_lowercase() == name) {
+                Some(entry) if entry.data == contents => {},
+                Some(_) => problems.push(format!("{} entry {} does not match {}", group, name, describe(path))),
+                None => problems.push(format!("{} entry {} from {} is missing", group, name, describe(path))),
+            }
+        }
+    }
+
+    match &record.backup {
+        Some(backup) if backup.is_file() => {
+            let original = match ice::read_ice(backup) {
+                Ok(o) => o,
+                Err(e) => {
+                    problems.push(format!("backup {} does not load: {:#}", backup.to_string_lossy(), e));
+                    return Ok(problems);
+                },
+            };
+            compare_originals(&original, &current, &expected, &renames, &mut problems);
+        },
+        Some(backup) => problems.push(format!("backup {} is missing", backup.to_string_lossy())),
+        None => {},
+    }
+
+    Ok(problems)
+}
+
+/// Check that entries not replaced by a patch are unchanged from the original,
+/// other than being renamed by the rules `renames` in turn.
+fn compare_originals(
+    original: &IceContents,
+    current: &IceContents,
+    patched: &[HashMap<String, PathBuf>; 2],
+    renames: &[RenameRules],
+    problems: &mut Vec<String>,
+) {
+    for &group in ice::GROUPS.iter() {
+        let entries = current.group(group);
+        for orig in original.group(group) {
+            let name = renames.iter().fold(orig.name.clone(), |n, r| r.apply(&n).into_owned());
+            if patched[ice::group_index(group)].contains_key(&name.to_lowercase()) {
+                continue;
+            }
+            let described = if name == orig.name {
+                name.clone()
+            } else {
+                format!("{} (renamed from {})", name, orig.name)
+            };
+            match entries.iter().find(|e| e.name == name) {
+                Some(entry) if entry.data == orig.data => {},
+                Some(_) => problems.push(format!("{} entry {} differs from the backup", group, described)),
+                None => problems.push(format!("{} entry {} from the backup is missing", group, described)),
+            }
+        }
+    }
+}